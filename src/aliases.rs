@@ -0,0 +1,305 @@
+/// Nick variants of one person (`alice_`, `alice|away`) resolved to one
+/// canonical nick, so that they share a chain
+use {
+    crate::{
+        casemapping::Casemapping, config, log_parse, nick_file, path_for_nick, raw_chain,
+        reverse_path,
+    },
+    std::{collections::HashMap, ffi::OsStr, fs, path},
+};
+
+type Fallible<T> = crate::Fallible<T>;
+
+/// Decorations after `-` or `_` that are stripped with `strip_suffixes`
+const AWAY_SUFFIXES: [&str; 14] = [
+    "away", "afk", "brb", "busy", "food", "home", "laptop", "lunch", "mobile", "off", "phone",
+    "sleep", "work", "zzz",
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Aliases {
+    casemapping: Casemapping,
+    strip_suffixes: bool,
+    file: Vec<(String, String)>, // `(variant, canonical)` as read from the alias file
+    map: HashMap<String, String>, // the same, lowercased with `casemapping`
+}
+
+fn trim_underscores(s: &str) -> &str {
+    s.trim_end_matches(['_', '`'])
+}
+
+/// `nick` without its away or client decorations:
+/// `alice__`, `alice|away`, `alice-phone` and `alice_afk` all give `alice`
+fn strip_suffix(nick: &str) -> &str {
+    let mut n = trim_underscores(nick);
+    if let Some(i) = n.find('|') {
+        n = &n[..i];
+    }
+    if let Some(i) = n.rfind(['-', '_']) {
+        if AWAY_SUFFIXES.contains(&&n[i + 1..]) {
+            n = &n[..i];
+        }
+    }
+    match trim_underscores(n) {
+        "" => nick,
+        n => n,
+    }
+}
+
+impl Aliases {
+    /// No alias file: nicks are only lowercased with `cm`, and maybe stripped
+    pub fn new(cm: Casemapping, strip_suffixes: bool) -> Self {
+        Aliases {
+            casemapping: cm,
+            strip_suffixes,
+            file: vec![],
+            map: HashMap::new(),
+        }
+    }
+
+    /// Read the alias file `p`. Each line holds the canonical nick of a person,
+    /// then their other nicks, separated by spaces. `#` starts a comment.
+    /// A nick can only be the alias of one canonical nick, which cannot be
+    /// an alias itself.
+    pub fn load(p: &path::Path, cm: Casemapping, strip_suffixes: bool) -> Fallible<Self> {
+        let s =
+            fs::read_to_string(p).map_err(|e| format!("cannot read alias file {:?}: {}", p, e))?;
+        let mut a = Aliases::new(cm, strip_suffixes);
+        let mut seen: HashMap<String, (String, usize)> = HashMap::new(); // with line numbers
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut nicks = line.split_whitespace().map(log_parse::normalize_nick);
+            let canonical = match nicks.next() {
+                Some(n) => n,
+                None => continue,
+            };
+            for nick in nicks {
+                if let Some((other, _)) = seen.get(&nick) {
+                    if *other != canonical {
+                        return Err(format!(
+                            "{:?}:{}: {:?} is already an alias of {:?}",
+                            p,
+                            i + 1,
+                            nick,
+                            other
+                        )
+                        .into());
+                    }
+                }
+                seen.insert(nick.clone(), (canonical.clone(), i + 1));
+                a.file.push((nick, canonical.clone()));
+            }
+        }
+        // `canonical` only resolves aliases in one step
+        for (nick, canonical) in a.file.iter() {
+            match seen.get(canonical) {
+                Some((other, line)) if other != canonical => {
+                    return Err(format!(
+                        "{:?}:{}: {:?} is an alias of {:?}, so {:?} must be one too",
+                        p, line, canonical, other, nick
+                    )
+                    .into())
+                }
+                _ => (),
+            }
+        }
+        a.set_casemapping(cm);
+        Ok(a)
+    }
+
+    /// Aliases as configured in `config`
+    pub fn from_config(config: &config::Config) -> Fallible<Self> {
+        match config.aliases {
+            Some(ref p) => Aliases::load(p, config.casemapping, config.strip_nick_suffixes),
+            None => Ok(Aliases::new(config.casemapping, config.strip_nick_suffixes)),
+        }
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        self.casemapping
+    }
+
+    pub fn set_casemapping(&mut self, cm: Casemapping) {
+        self.casemapping = cm;
+        self.map = self
+            .file
+            .iter()
+            .map(|(nick, canonical)| (cm.fold(nick), cm.fold(canonical)))
+            .collect();
+    }
+
+    /// `m` with its nicks replaced by their canonical nicks
+    pub fn canonical_keys<V: Clone>(&self, m: &HashMap<String, V>) -> HashMap<String, V> {
+        m.iter()
+            .map(|(nick, v)| (self.canonical(nick), v.clone()))
+            .collect()
+    }
+
+    /// Number of nicks with an alias
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Canonical name of the chain `name` (a nick, or `nick@period`)
+    pub fn canonical(&self, name: &str) -> String {
+        let (nick, period) = nick_file::split_period(name);
+        let nick = self.casemapping.fold(nick);
+        let mut c = match self.map.get(&nick) {
+            Some(c) => c.clone(),
+            None if self.strip_suffixes => {
+                let stripped = strip_suffix(&nick);
+                self.map
+                    .get(stripped)
+                    .map_or(stripped, |c| c.as_str())
+                    .to_string()
+            }
+            None => nick,
+        };
+        if let Some(period) = period {
+            c.push(nick_file::PERIOD_SEP);
+            c.push_str(period);
+        }
+        c
+    }
+
+    /// Rename the chains of `data_dir` to their canonical names, merging
+    /// chains that end up with the same name (after a change of casemapping
+    /// or of aliases). Returns how many chains were renamed.
+    pub fn rename_chains(&self, data_dir: &path::Path) -> Fallible<usize> {
+        if !data_dir.exists() {
+            return Ok(0);
+        }
+        let mut n = 0;
+        for p in fs::read_dir(data_dir)? {
+            let path = p?.path();
+            if path.extension() != Some(OsStr::new("bin")) {
+                continue;
+            }
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            let name = match nick_file::decode_chain(&stem) {
+                Some(name) => name,
+                None => continue,
+            };
+            let canonical = self.canonical(&name);
+            if canonical == name {
+                continue;
+            }
+            let target = path_for_nick(data_dir, &canonical)?;
+            let rev = reverse_path(&path);
+            if target.exists() {
+                info!("merge chain {:?} into {:?}", name, canonical);
+                let c = raw_chain::RawChainPair::load(&target)?;
+                let other = raw_chain::RawChainPair::load(&path)?;
+                // orders can differ per nick, keep the lower one
+                let order = c.fwd.order.min(other.fwd.order);
+                if order < c.fwd.order {
+                    info!(
+                        "chain {:?} goes from order {} to the order {} of {:?}",
+                        canonical, c.fwd.order, order, name
+                    );
+                }
+                let mut c = c.reduce(order);
+                c.merge(&other.reduce(order))?;
+                c.save(&target)?;
+                fs::remove_file(&path)?;
+                if rev.exists() {
+                    fs::remove_file(&rev)?;
+                }
+            } else {
+                info!("rename chain {:?} to {:?}", name, canonical);
+                fs::rename(&path, &target)?;
+                if rev.exists() {
+                    fs::rename(&rev, reverse_path(&target))?;
+                }
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    #[test]
+    fn strip_suffixes() {
+        for (nick, stripped) in &[
+            ("alice", "alice"),
+            ("alice__", "alice"),
+            ("alice`", "alice"),
+            ("alice|away", "alice"),
+            ("alice|", "alice"),
+            ("alice-phone", "alice"),
+            ("alice_afk_", "alice"),
+            ("alice_smith", "alice_smith"),
+            ("mary-jane", "mary-jane"),
+            ("__", "__"),
+            ("|away", "|away"),
+        ] {
+            assert_eq!(strip_suffix(nick), *stripped, "{}", nick);
+        }
+    }
+
+    #[test]
+    fn canonical() {
+        let dir = test_dir("aliases-canonical");
+        let p = dir.join("aliases.txt");
+        fs::write(&p, "alice ally A[1] # comment\n\n# bob\nbob @bobby\n").unwrap();
+        let a = Aliases::load(&p, Casemapping::Rfc1459, true).unwrap();
+        assert_eq!(a.len(), 3);
+        assert_eq!(a.canonical("Ally"), "alice");
+        assert_eq!(a.canonical("a{1}"), "alice");
+        assert_eq!(a.canonical("ally|away"), "alice");
+        assert_eq!(a.canonical("bobby@2020"), "bob@2020");
+        assert_eq!(a.canonical("Carol_"), "carol");
+        let a = Aliases::load(&p, Casemapping::Ascii, false).unwrap();
+        assert_eq!(a.canonical("a{1}"), "a{1}");
+        assert_eq!(a.canonical("Carol_"), "carol_");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_alias_files() {
+        let dir = test_dir("aliases-invalid");
+        let p = dir.join("aliases.txt");
+        let load = |s: &str| {
+            fs::write(&p, s).unwrap();
+            Aliases::load(&p, Casemapping::default(), false).map_err(|e| e.to_string())
+        };
+        assert!(load("alice ally\nalice ally\n").is_ok());
+        let e = load("alice ally\nbob ally\n").unwrap_err();
+        assert!(
+            e.ends_with(":2: \"ally\" is already an alias of \"alice\""),
+            "{}",
+            e
+        );
+        let e = load("bob alice\nalice ally\n").unwrap_err();
+        assert!(e.contains(":1: \"alice\" is an alias of \"bob\""), "{}", e);
+        let e = load("alice ally\nally al\n").unwrap_err();
+        assert!(e.contains(":1: \"ally\" is an alias of \"alice\""), "{}", e);
+        assert!(load("").is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rename_chains_of_different_orders() {
+        let dir = test_dir("aliases-orders");
+        let mut alice = raw_chain::RawChainPair::empty(3);
+        alice.feed_str("hello there you");
+        alice.save(&path_for_nick(&dir, "alice").unwrap()).unwrap();
+        let mut away = raw_chain::RawChainPair::empty(1);
+        away.feed_str("brb food");
+        away.save(&path_for_nick(&dir, "alice_").unwrap()).unwrap();
+        let aliases = Aliases::new(Casemapping::default(), true);
+        assert_eq!(aliases.rename_chains(&dir).unwrap(), 1);
+        let p = path_for_nick(&dir, "alice").unwrap();
+        assert!(!path_for_nick(&dir, "alice_").unwrap().exists());
+        let c = raw_chain::RawChainPair::load(&p).unwrap();
+        assert_eq!(c.fwd.order, 1);
+        assert_eq!(c.rev.order, 1);
+        assert_eq!(c.fwd.vocabulary(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
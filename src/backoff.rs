@@ -0,0 +1,346 @@
+/// Generation mixing the orders of a chain, backing off to lower orders
+/// when higher ones cannot continue a sentence
+use {
+    crate::{
+        raw_chain::{RawChain, RawChainPair},
+        reverse_path, save_chain, Fallible,
+    },
+    rand::Rng,
+    std::{collections::HashMap, path},
+};
+
+/// How much more likely ending a sentence gets, from the shortest target
+/// length to the longest
+const END_BOOST: f64 = 4.;
+
+/// A chain and its lower orders, derived from it by `RawChain::reduce`
+#[derive(Clone)]
+pub struct Backoff {
+    fwd: Vec<RawChain>, // of increasing orders, the last one is the chain itself
+    rev: Vec<RawChain>, // same for the reverse chain
+    weights: Vec<f64>,  // one per order
+}
+
+/// Weight of the end of the sentence after `len` bytes, relative to the
+/// other tokens: never before `min` if something else can follow, more and
+/// more likely up to `max`, and always after it if possible
+fn end_bias(len: usize, (min, max): (usize, usize)) -> f64 {
+    if len < min {
+        0.
+    } else if len >= max {
+        f64::INFINITY
+    } else {
+        1. + END_BOOST * (len - min) as f64 / (max - min) as f64
+    }
+}
+
+/// One of `m`'s keys, picked in proportion to its weight given by
+/// `weight` from the key and its value
+fn pick<T, V: Copy, F: Fn(&T, V) -> f64>(m: &HashMap<T, V>, weight: F) -> Option<&T> {
+    let total: f64 = m.iter().map(|(k, &n)| weight(k, n)).sum();
+    if total.is_nan() || total <= 0. {
+        return None;
+    }
+    let mut x = rand::thread_rng().gen_range(0., total);
+    let mut found = None;
+    for (k, &n) in m.iter() {
+        let w = weight(k, n);
+        if w > 0. {
+            found = Some(k);
+            if x < w {
+                break;
+            }
+            x -= w;
+        }
+    }
+    found
+}
+
+impl Backoff {
+    /// Mix the orders of `chain` with `weights`, one per order. Orders
+    /// above the number of weights get the last one.
+    pub fn new(chain: RawChainPair, weights: &[f64]) -> Self {
+        let top = chain.fwd.order;
+        let (mut fwd, mut rev) = (vec![chain.fwd], vec![chain.rev]);
+        for order in (1..top).rev() {
+            let f = fwd.last().unwrap().clone().reduce(order);
+            let r = rev.last().unwrap().clone().reduce(order);
+            fwd.push(f);
+            rev.push(r);
+        }
+        fwd.reverse();
+        rev.reverse();
+        let last = weights.len().max(1);
+        Backoff {
+            fwd,
+            rev,
+            weights: (1..=top)
+                .map(|order| weights.get(order.min(last) - 1).cloned().unwrap_or(1.))
+                .collect(),
+        }
+    }
+
+    /// Only the order of `chain`, to generate from it without back-off
+    pub fn single(chain: RawChainPair) -> Self {
+        Backoff {
+            fwd: vec![chain.fwd],
+            rev: vec![chain.rev],
+            weights: vec![1.],
+        }
+    }
+
+    /// Write the chain itself and its reverse chain, like `RawChainPair::save`
+    pub fn save(&self, p: &path::Path) -> Fallible<()> {
+        save_chain(p, self.order(), self.fwd.last().unwrap())?;
+        save_chain(&reverse_path(p), self.order(), self.rev.last().unwrap())
+    }
+
+    /// Highest order
+    fn order(&self) -> usize {
+        self.fwd.last().unwrap().order
+    }
+
+    /// Feed `msg` like `RawChainPair::feed_str`, into every order
+    pub fn feed_str(&mut self, msg: &str) {
+        let mut tokens: Vec<String> = msg.split(' ').map(|s| s.to_owned()).collect();
+        for c in self.fwd.iter_mut() {
+            c.feed(&tokens);
+        }
+        tokens.reverse();
+        for c in self.rev.iter_mut() {
+            c.feed(&tokens);
+        }
+    }
+
+    /// Tokens that can follow the last `order()` tokens of a sentence
+    /// (padded with `None` at its start), with their probabilities. They
+    /// are the ones the highest order that knows `context` has seen after
+    /// it, and their probabilities in this order and the lower ones are
+    /// averaged with the weights of the orders (or only their counts are
+    /// used if these weights are all 0).
+    fn nexts<'a>(
+        &self,
+        chains: &'a [RawChain],
+        context: &[Option<String>],
+    ) -> Option<HashMap<&'a Option<String>, f64>> {
+        let n = self.order();
+        // a lower order knows the context of any higher one that does
+        let known: Vec<_> = chains
+            .iter()
+            .zip(self.weights.iter())
+            .filter_map(|(c, &w)| {
+                let nexts = c.map.get(&context[n - c.order..])?;
+                let total = nexts.values().sum::<usize>();
+                if total == 0 {
+                    None
+                } else {
+                    Some((nexts, w / total as f64))
+                }
+            })
+            .collect();
+        let top = known.last()?.0;
+        let mixed = known.iter().any(|&(_, w)| w > 0.);
+        let p = |next: &Option<String>, count: usize| {
+            if !mixed {
+                return count as f64;
+            }
+            known
+                .iter()
+                .map(|&(nexts, w)| w * nexts.get(next).cloned().unwrap_or(0) as f64)
+                .sum()
+        };
+        Some(top.iter().map(|(next, &n)| (next, p(next, n))).collect())
+    }
+
+    /// Continue a sentence from `context` until its end, steering its
+    /// length in bytes towards `target`
+    fn walk(
+        &self,
+        chains: &[RawChain],
+        mut context: Vec<Option<String>>,
+        mut words: Vec<String>,
+        target: (usize, usize),
+    ) -> Vec<String> {
+        let mut len = words.iter().map(|w| w.len() + 1).sum::<usize>();
+        while let Some(nexts) = self.nexts(chains, &context) {
+            let bias = end_bias(len.saturating_sub(1), target);
+            if bias.is_infinite() && nexts.keys().any(|w| w.is_none()) {
+                break;
+            }
+            let end = |w: &&Option<String>, p: f64| match w {
+                None => p * bias,
+                Some(_) => p,
+            };
+            let w = match pick(&nexts, end) {
+                Some(Some(w)) => w.clone(),
+                _ => break,
+            };
+            len += w.len() + 1;
+            context.remove(0);
+            context.push(Some(w.clone()));
+            words.push(w);
+        }
+        words
+    }
+
+    /// Random sentence, of a length in `target` if possible
+    pub fn sentence(&self, target: (usize, usize)) -> String {
+        self.walk(&self.fwd, vec![None; self.order()], vec![], target)
+            .join(" ")
+    }
+
+    /// States of the highest order that end with `word`, with the number
+    /// of times they were seen: wherever `word` was in a sentence
+    pub fn seeds(&self, word: &str) -> HashMap<&[Option<String>], usize> {
+        let word = Some(word.to_string());
+        self.fwd
+            .last()
+            .unwrap()
+            .map
+            .iter()
+            .filter(|(state, _)| state.last() == Some(&word))
+            .map(|(state, nexts)| (state.as_slice(), nexts.values().sum()))
+            .collect()
+    }
+
+    /// Sentence grown from one of `seeds`, keeping the words of the state:
+    /// rightwards with the chain, aiming for half of `target`, then
+    /// leftwards with the reverse chain unless the state starts a sentence.
+    /// Empty if there are no seeds.
+    pub fn around(
+        &self,
+        seeds: &HashMap<&[Option<String>], usize>,
+        (min, max): (usize, usize),
+    ) -> String {
+        let state = match pick(seeds, |_, n| n as f64) {
+            Some(state) => state.to_vec(),
+            None => return String::new(),
+        };
+        let starts = state[0].is_none();
+        let words = state.iter().filter_map(|w| w.clone()).collect();
+        let right = self.walk(&self.fwd, state, words, (min / 2, max / 2));
+        if starts {
+            return right.join(" ");
+        }
+        // the reverse chain reads the sentence from its end
+        let n = self.order();
+        let mut context = vec![None; n];
+        context.extend(right.iter().rev().map(|w| Some(w.clone())));
+        let context = context[context.len() - n..].to_vec();
+        let len = right.iter().map(|w| w.len() + 1).sum::<usize>();
+        let target = (min.saturating_sub(len), max.saturating_sub(len));
+        let mut words = self.walk(&self.rev, context, vec![], target);
+        words.reverse();
+        words.extend(right);
+        words.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(c: &RawChainPair) -> Backoff {
+        Backoff::single(c.clone())
+    }
+
+    #[test]
+    fn follows_counts() {
+        let mut c = RawChainPair::empty(1);
+        for _ in 0..99 {
+            c.feed_str("x a");
+        }
+        c.feed_str("x b");
+        let b = single(&c);
+        let n = 10_000;
+        let seeds = b.seeds("x");
+        let rare = (0..n)
+            .filter(|_| b.around(&seeds, (0, 100)) == "x b")
+            .count();
+        // 1% expected, the odds of leaving this range are negligible
+        assert!(rare > n / 200 && rare < n * 3 / 100, "{} of {}", rare, n);
+    }
+
+    #[test]
+    fn around_word_mid_sentence() {
+        let mut c = RawChainPair::empty(2);
+        c.feed_str("the cat sat on the mat");
+        c.feed_str("a dog ran");
+        let b = single(&c);
+        assert_eq!(
+            b.around(&b.seeds("sat"), (0, 100)),
+            "the cat sat on the mat"
+        );
+        assert_eq!(
+            b.around(&b.seeds("mat"), (0, 100)),
+            "the cat sat on the mat"
+        );
+        assert_eq!(b.around(&b.seeds("a"), (0, 100)), "a dog ran");
+        assert!(b.seeds("cow").is_empty());
+        assert_eq!(b.around(&b.seeds("cow"), (0, 100)), "");
+    }
+
+    #[test]
+    fn highest_order_first() {
+        let mut c = RawChainPair::empty(2);
+        c.feed_str("a b c");
+        c.feed_str("x b d");
+        let b = Backoff::new(c, &[1., 1.]);
+        // order 1 alone could follow `b` with `d`
+        let seeds = b.seeds("a");
+        for _ in 0..100 {
+            assert_eq!(b.around(&seeds, (0, 100)), "a b c");
+        }
+    }
+
+    #[test]
+    fn orders_above_weights() {
+        let mut c = RawChainPair::empty(3);
+        c.feed_str("a b c d");
+        c.feed_str("x b c e");
+        let b = Backoff::new(c, &[1., 1.]);
+        assert_eq!(b.fwd.iter().map(|c| c.order).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(b.weights, [1., 1., 1.]);
+        // only order 3 tells `a b c` from `x b c`
+        let seeds = b.seeds("a");
+        for _ in 0..100 {
+            assert_eq!(b.around(&seeds, (0, 100)), "a b c d");
+        }
+    }
+
+    #[test]
+    fn end_bias_bounds() {
+        assert_eq!(end_bias(0, (5, 40)), 0.);
+        assert_eq!(end_bias(4, (5, 40)), 0.);
+        assert_eq!(end_bias(5, (5, 40)), 1.);
+        assert!(end_bias(30, (5, 40)) > end_bias(10, (5, 40)));
+        assert!(end_bias(39, (5, 40)) < 1. + END_BOOST);
+        assert_eq!(end_bias(40, (5, 40)), f64::INFINITY);
+        assert_eq!(end_bias(100, (5, 40)), f64::INFINITY);
+    }
+
+    /// A chain of sentences of any number of `blah`s, mostly short ones
+    fn blah() -> Backoff {
+        let mut c = RawChainPair::empty(1);
+        c.feed_str("blah");
+        c.feed_str("blah blah");
+        single(&c)
+    }
+
+    #[test]
+    fn sentence_steered_to_target() {
+        let b = blah();
+        for &(min, max) in &[(5, 40), (100, 300)] {
+            for _ in 0..100 {
+                let s = b.sentence((min, max));
+                // it can go past `max` by the word it was at
+                assert!(s.len() >= min && s.len() < max + 5, "{}: {:?}", s.len(), s);
+            }
+        }
+        let seeds = b.seeds("blah");
+        for _ in 0..100 {
+            let s = b.around(&seeds, (100, 300));
+            assert!(s.len() >= 100 && s.len() < 300 + 10, "{}: {:?}", s.len(), s);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+/// How nicks are lowercased. With `rfc1459`, `[]\~` are the uppercase
+/// versions of `{}|^`, `strict-rfc1459` leaves out `~`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Casemapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl Default for Casemapping {
+    /// What servers use when they do not advertise a mapping
+    fn default() -> Self {
+        Casemapping::Rfc1459
+    }
+}
+
+impl Casemapping {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "ascii" => Some(Casemapping::Ascii),
+            "rfc1459" => Some(Casemapping::Rfc1459),
+            "strict-rfc1459" => Some(Casemapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    /// Lowercase version of `nick`
+    pub fn fold(self, nick: &str) -> String {
+        nick.chars()
+            .map(|c| match (self, c) {
+                (_, 'A'..='Z') => c.to_ascii_lowercase(),
+                (Casemapping::Ascii, _) => c,
+                (_, '[') => '{',
+                (_, ']') => '}',
+                (_, '\\') => '|',
+                (Casemapping::Rfc1459, '~') => '^',
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+/// Mapping advertised by the `CASEMAPPING` token of an `RPL_ISUPPORT` reply
+pub fn from_isupport(args: &[String]) -> Option<Casemapping> {
+    args.iter()
+        .filter_map(|a| a.strip_prefix("CASEMAPPING="))
+        .next()
+        .and_then(Casemapping::from_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold() {
+        assert_eq!(Casemapping::Ascii.fold("Al[i]ce\\~"), "al[i]ce\\~");
+        assert_eq!(Casemapping::Rfc1459.fold("Al[i]ce\\~"), "al{i}ce|^");
+        assert_eq!(Casemapping::StrictRfc1459.fold("Al[i]ce\\~"), "al{i}ce|~");
+        // only ASCII letters are lowercased
+        assert_eq!(Casemapping::Rfc1459.fold("ÉLISE"), "Élise");
+    }
+
+    #[test]
+    fn isupport() {
+        let args = |s: &str| s.split(' ').map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            from_isupport(&args("bot CHANTYPES=# CASEMAPPING=ascii NICKLEN=30")),
+            Some(Casemapping::Ascii)
+        );
+        assert_eq!(
+            from_isupport(&args("bot CASEMAPPING=strict-rfc1459")),
+            Some(Casemapping::StrictRfc1459)
+        );
+        assert_eq!(from_isupport(&args("bot CASEMAPPING=rfc7613")), None);
+        assert_eq!(from_isupport(&args("bot NICKLEN=30")), None);
+    }
+}
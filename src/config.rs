@@ -0,0 +1,343 @@
+/// Configuration file
+use {
+    crate::casemapping::Casemapping,
+    std::{collections::HashMap, path, str::FromStr},
+};
+
+type Fallible<T> = crate::Fallible<T>;
+
+pub const DEFAULT_PATH: &str = "./charliebot.toml";
+
+/// Highest Markov order accepted
+pub const MAX_ORDER: usize = 6;
+
+/// Toplevel configuration
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_data_dir")]
+    pub data_dir: path::PathBuf,
+    /// How nicks are lowercased by `generate`, and by `serve` until
+    /// the server tells its own mapping
+    #[serde(default)]
+    pub casemapping: Casemapping,
+    /// File mapping nick variants to the canonical nick of each person
+    pub aliases: Option<path::PathBuf>,
+    /// Also resolve `alice_`, `alice|away` or `alice-phone` to `alice`
+    #[serde(default)]
+    pub strip_nick_suffixes: bool,
+    /// Markov order of the chains built by `generate` (unless `--order` is
+    /// given) or created by `serve` when learning, if `backoff` is not set
+    #[serde(default = "default_order")]
+    pub order: usize,
+    /// Order for some nicks, overriding `order` and `generate --order`
+    #[serde(default)]
+    pub orders: HashMap<String, usize>,
+    /// Mix several orders of each chain when replying
+    pub backoff: Option<BackoffConfig>,
+    pub irc: Option<IrcConfig>,
+    /// Custom log formats, by name
+    #[serde(default)]
+    pub formats: HashMap<String, RegexFormatConfig>,
+}
+
+/// Generation with back-off from the highest order of a chain to lower ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackoffConfig {
+    /// Weights of the orders 1, 2, ..., N: at each word, the highest order
+    /// that can continue the sentence gives the next words, and their
+    /// probabilities in it and in the lower orders are mixed by these weights.
+    /// Chains of a higher order get the last weight for the orders above N.
+    pub weights: Vec<f64>,
+}
+
+/// How to connect to IRC, and what to answer to
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IrcConfig {
+    pub server: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+    pub nickname: String,
+    pub channels: Vec<String>,
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Feed channel messages into the chains while serving
+    #[serde(default)]
+    pub learn: bool,
+    /// Seconds between two saves of learnt chains
+    #[serde(default = "default_save_interval")]
+    pub save_interval: u64,
+}
+
+/// Log format defined by a regex with named captures `nick` and `msg`,
+/// and optionally `date` (year, month and day, like `2020-02-29`,
+/// `2020/2/29` or `20200229`) and `time`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegexFormatConfig {
+    pub line: String,
+    /// Lines matching this regex are ignored (joins, parts, etc.)
+    pub skip: Option<String>,
+}
+
+fn default_data_dir() -> path::PathBuf {
+    crate::DATA_DIR.into()
+}
+fn default_order() -> usize {
+    1
+}
+fn default_port() -> u16 {
+    6697
+}
+fn default_tls() -> bool {
+    true
+}
+fn default_prefix() -> String {
+    "!charlie".to_string()
+}
+fn default_save_interval() -> u64 {
+    300
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: default_data_dir(),
+            casemapping: Casemapping::default(),
+            aliases: None,
+            strip_nick_suffixes: false,
+            order: default_order(),
+            orders: HashMap::new(),
+            backoff: None,
+            irc: None,
+            formats: HashMap::new(),
+        }
+    }
+}
+
+fn invalid<T>(key: &str, msg: &str) -> Fallible<T> {
+    Err(format!("invalid value for `{}`: {}", key, msg).into())
+}
+
+impl FromStr for Config {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parse and validate the config contained in `s`
+    fn from_str(s: &str) -> Fallible<Self> {
+        let c: Config = toml::from_str(s)?;
+        c.validate()?;
+        Ok(c)
+    }
+}
+
+impl Config {
+    /// Read and validate the config file at `p`
+    pub fn load(p: &path::Path) -> Fallible<Self> {
+        let s = std::fs::read_to_string(p)
+            .map_err(|e| format!("cannot read config file {:?}: {}", p, e))?;
+        s.parse::<Config>()
+            .map_err(|e| format!("in config file {:?}: {}", p, e).into())
+    }
+
+    /// Read the config file at `p` if it exists, use defaults otherwise
+    pub fn load_or_default(p: &path::Path) -> Fallible<Self> {
+        if p.exists() {
+            Config::load(p)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    /// Check values that deserialization alone cannot catch
+    pub fn validate(&self) -> Fallible<()> {
+        if self.data_dir.as_os_str().is_empty() {
+            return invalid("data_dir", "must not be empty");
+        }
+        if self.order == 0 || self.order > MAX_ORDER {
+            return invalid("order", &format!("must be between 1 and {}", MAX_ORDER));
+        }
+        for (nick, order) in self.orders.iter() {
+            if *order == 0 || *order > MAX_ORDER {
+                return invalid(
+                    &format!("orders.{}", nick),
+                    &format!("must be between 1 and {}", MAX_ORDER),
+                );
+            }
+        }
+        if let Some(ref b) = self.backoff {
+            b.validate()?;
+        }
+        if let Some(ref p) = self.aliases {
+            if p.as_os_str().is_empty() {
+                return invalid("aliases", "must not be empty");
+            }
+        }
+        if let Some(ref irc) = self.irc {
+            irc.validate()?;
+        }
+        for (name, f) in self.formats.iter() {
+            if crate::log_parse::FORMATS.contains(&name.as_str()) {
+                return invalid(
+                    &format!("formats.{}", name),
+                    "this is the name of a builtin format",
+                );
+            }
+            f.validate(name)?;
+        }
+        Ok(())
+    }
+
+    /// Order of new chains, unless overridden per nick: the highest order
+    /// mixed by back-off if it is enabled, `order` otherwise
+    pub fn chain_order(&self) -> usize {
+        match self.backoff {
+            Some(ref b) => b.weights.len(),
+            None => self.order,
+        }
+    }
+
+    /// Access the IRC section, which is mandatory for `serve`
+    pub fn irc(&self) -> Fallible<&IrcConfig> {
+        self.irc
+            .as_ref()
+            .ok_or_else(|| "missing section `[irc]` in config".into())
+    }
+}
+
+impl RegexFormatConfig {
+    fn validate(&self, name: &str) -> Fallible<()> {
+        let key = format!("formats.{}.line", name);
+        let re = match regex::Regex::new(&self.line) {
+            Ok(re) => re,
+            Err(e) => return invalid(&key, &e.to_string()),
+        };
+        let names: Vec<_> = re.capture_names().flatten().collect();
+        for n in &["nick", "msg"] {
+            if !names.contains(n) {
+                return invalid(&key, &format!("missing named capture `(?P<{}>...)`", n));
+            }
+        }
+        if let Some(ref skip) = self.skip {
+            if let Err(e) = regex::Regex::new(skip) {
+                return invalid(&format!("formats.{}.skip", name), &e.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BackoffConfig {
+    fn validate(&self) -> Fallible<()> {
+        if self.weights.is_empty() || self.weights.len() > MAX_ORDER {
+            return invalid(
+                "backoff.weights",
+                &format!("must have between 1 and {} weights", MAX_ORDER),
+            );
+        }
+        if self.weights.iter().any(|w| !w.is_finite() || *w < 0.) {
+            return invalid("backoff.weights", "weights must be non-negative numbers");
+        }
+        Ok(())
+    }
+}
+
+impl IrcConfig {
+    fn validate(&self) -> Fallible<()> {
+        if self.server.trim().is_empty() {
+            return invalid("irc.server", "must not be empty");
+        }
+        if self.port == 0 {
+            return invalid("irc.port", "must be non-zero");
+        }
+        if self.nickname.trim().is_empty() || self.nickname.contains(char::is_whitespace) {
+            return invalid("irc.nickname", "must be a non-empty word");
+        }
+        if self.channels.is_empty() {
+            return invalid("irc.channels", "must contain at least one channel");
+        }
+        for chan in self.channels.iter() {
+            if !(chan.starts_with('#') || chan.starts_with('&')) || chan.contains(' ') {
+                return invalid("irc.channels", &format!("{:?} is not a channel name", chan));
+            }
+        }
+        if self.prefix.trim().is_empty() {
+            return invalid("irc.prefix", "must not be empty");
+        }
+        if self.save_interval == 0 {
+            return invalid("irc.save_interval", "must be non-zero");
+        }
+        Ok(())
+    }
+
+    /// Configuration for the IRC client
+    pub fn to_client_config(&self) -> irc::client::prelude::Config {
+        irc::client::prelude::Config {
+            nickname: Some(self.nickname.clone()),
+            server: Some(self.server.clone()),
+            port: Some(self.port),
+            channels: Some(self.channels.clone()),
+            use_ssl: Some(self.tls),
+            ..irc::client::prelude::Config::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IRC: &str =
+        "[irc]\nserver = \"irc.example.org\"\nnickname = \"charlie\"\nchannels = [\"#chan\"]\n";
+
+    /// Error from parsing `s`, which must name `key`
+    fn error(s: &str, key: &str) -> String {
+        let e = match s.parse::<Config>() {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("{:?} was accepted", s),
+        };
+        assert!(e.contains(&format!("`{}`", key)), "{:?}: {}", s, e);
+        e
+    }
+
+    #[test]
+    fn valid() {
+        let c: Config = format!("order = 3\n[orders]\nalice = 2\n{}port = 6697\n", IRC)
+            .parse()
+            .unwrap();
+        assert_eq!((c.order, c.orders["alice"]), (3, 2));
+        assert_eq!(c.irc().unwrap().port, 6697);
+        assert_eq!("".parse::<Config>().unwrap().order, default_order());
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        error(&format!("{}port = 0\n", IRC), "irc.port");
+        error(&format!("{}port = 70000\n", IRC), "irc.port");
+        error(&format!("{}port = \"x\"\n", IRC), "irc.port");
+        error("order = 0\n", "order");
+        error(&format!("order = {}\n", MAX_ORDER + 1), "order");
+        error("[orders]\nalice = 0\n", "orders.alice");
+        error("[orders]\nalice = -1\n", "orders.alice");
+        error(
+            "[formats.mine]\nline = \"(?P<nick>\\\\S+\"\n",
+            "formats.mine.line",
+        );
+        error(
+            "[formats.mine]\nline = \"(?P<nick>\\\\S+)\"\n",
+            "formats.mine.line",
+        );
+        error(
+            "[formats.weechat]\nline = \"(?P<nick>\\\\S+) (?P<msg>.*)\"\n",
+            "formats.weechat",
+        );
+        error("[backoff]\nweights = []\n", "backoff.weights");
+        let e = error("colour = \"red\"\n", "colour");
+        assert!(e.contains("unknown field"), "{}", e);
+        let e = error(&format!("{}colour = \"red\"\n", IRC), "colour");
+        assert!(e.contains("unknown field"), "{}", e);
+    }
+}
@@ -0,0 +1,1449 @@
+/// Build chains from log files
+use {
+    crate::{
+        aliases, config, log_parse, nick_file, path_for_nick, progress, raw_chain, reverse_path,
+        sources, spill, Fallible,
+    },
+    std::{
+        collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+        fs, io, path,
+        sync::Mutex,
+        thread,
+    },
+};
+
+/// Which entries to train on
+#[derive(Clone, Debug, Default)]
+struct Filter {
+    since: Option<String>, // `YYYY[-MM[-DD]]`, inclusive
+    until: Option<String>, // same, inclusive
+    only: Option<HashSet<String>>,
+    exclude: HashSet<String>,
+    no_actions: bool, // train on messages only, not on `/me` actions
+}
+
+/// Check that `s` is a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` date
+fn parse_date(opt: &str, s: &str) -> Fallible<String> {
+    let ok = [4, 7, 10].contains(&s.len())
+        && s.bytes().enumerate().all(|(i, c)| match i {
+            4 | 7 => c == b'-',
+            _ => c.is_ascii_digit(),
+        });
+    if ok {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "{} expects a YYYY, YYYY-MM or YYYY-MM-DD date, not {:?}",
+            opt, s
+        )
+        .into())
+    }
+}
+
+/// Normalized nicks of a comma separated list
+fn parse_nicks(s: &str) -> HashSet<String> {
+    s.split(',')
+        .map(log_parse::normalize_nick)
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+/// Sorted, comma separated `nicks`
+fn join_nicks(nicks: &HashSet<String>) -> String {
+    let mut nicks: Vec<_> = nicks.iter().map(|n| n.as_str()).collect();
+    nicks.sort_unstable();
+    nicks.join(",")
+}
+
+impl Filter {
+    /// Resolve the nicks of `--only` and `--exclude` like those of entries
+    fn resolve_nicks(&mut self, aliases: &aliases::Aliases) {
+        if let Some(ref mut only) = self.only {
+            *only = only.iter().map(|n| aliases.canonical(n)).collect();
+        }
+        self.exclude = self.exclude.iter().map(|n| aliases.canonical(n)).collect();
+    }
+
+    /// Whether `e` is to be trained on. Notices and system lines never are,
+    /// and entries without a date are left out when there is a date range.
+    fn accepts(&self, e: &log_parse::Entry) -> bool {
+        match e.kind {
+            log_parse::LineKind::Message => (),
+            log_parse::LineKind::Action if !self.no_actions => (),
+            _ => return false,
+        }
+        if let Some(ref since) = self.since {
+            if e.date.is_empty() || *e.date < **since {
+                return false;
+            }
+        }
+        if let Some(ref until) = self.until {
+            // compare only the precision of `until`, it includes its whole year or month
+            let date = e.date.get(..until.len()).unwrap_or(&e.date);
+            if date.is_empty() || date > until.as_str() {
+                return false;
+            }
+        }
+        if let Some(ref only) = self.only {
+            if !only.contains(&e.nick) {
+                return false;
+            }
+        }
+        !self.exclude.contains(&e.nick)
+    }
+}
+
+/// Time buckets of time-sliced chains
+#[derive(Clone, Copy, Debug)]
+enum Bucket {
+    Year,
+    Month,
+}
+
+impl Bucket {
+    fn from_name(s: &str) -> Fallible<Self> {
+        match s {
+            "year" => Ok(Bucket::Year),
+            "month" => Ok(Bucket::Month),
+            _ => Err(format!("unknown bucket {:?} (known: year, month)", s).into()),
+        }
+    }
+
+    /// Period of `date` (`YYYY-MM-DD`), if it has one
+    fn period(self, date: &str) -> Option<&str> {
+        let len = match self {
+            Bucket::Year => 4,
+            Bucket::Month => 7,
+        };
+        let period = date.get(..len)?;
+        if parse_date("", period).is_ok() {
+            Some(period)
+        } else {
+            None
+        }
+    }
+}
+
+/// Markov order of the chains to write
+#[derive(Clone, Copy, Debug)]
+enum Order {
+    Fixed(usize),
+    /// Depends on the number of lines of each nick, see `auto_order`
+    Auto,
+}
+
+/// Highest order picked by `Order::Auto`
+const AUTO_MAX_ORDER: usize = 3;
+
+/// Order for a nick with `lines` lines: quiet nicks need a low order to
+/// say something else than what they said, chatty ones can afford a
+/// higher one to be more coherent
+fn auto_order(lines: usize) -> usize {
+    match lines {
+        0..=1_999 => 1,
+        2_000..=19_999 => 2,
+        _ => AUTO_MAX_ORDER,
+    }
+}
+
+impl Order {
+    fn from_name(s: &str) -> Fallible<Self> {
+        match s.parse() {
+            _ if s == "auto" => Ok(Order::Auto),
+            Ok(n) if (1..=config::MAX_ORDER).contains(&n) => Ok(Order::Fixed(n)),
+            _ => Err(format!(
+                "--order expects auto or a number between 1 and {}, not {:?}",
+                config::MAX_ORDER,
+                s
+            )
+            .into()),
+        }
+    }
+}
+
+/// Options for `generate`
+#[derive(Clone, Debug)]
+pub struct GenerateOpts {
+    merge: bool, // add to existing chains instead of replacing them
+    format: String,
+    encoding: log_parse::Encoding, // for lines that are not valid UTF-8
+    config: Option<String>,
+    dry_run: Option<usize>,       // only print that many parsed entries
+    jobs: usize,                  // number of files parsed in parallel
+    memory_budget: Option<usize>, // in bytes, for the chains being built
+    json: bool,                   // print the summary as JSON
+    filter: Filter,
+    min_lines: usize,               // nicks with fewer lines read get no chain
+    bucket: Option<Bucket>,         // also build one chain per nick and period
+    aliases: aliases::Aliases,      // from the config
+    order: Option<Order>,           // from the config if not given
+    orders: HashMap<String, usize>, // per nick, from the config
+    build_order: usize,             // of the chains while they are built, before `reduce`
+    inputs: Vec<String>,
+}
+
+impl Default for GenerateOpts {
+    fn default() -> Self {
+        GenerateOpts {
+            merge: false,
+            format: log_parse::DEFAULT_FORMAT.to_string(),
+            encoding: log_parse::Encoding::Lossy,
+            config: None,
+            dry_run: None,
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            memory_budget: None,
+            json: false,
+            filter: Filter::default(),
+            min_lines: 0,
+            bucket: None,
+            aliases: aliases::Aliases::default(),
+            order: None,
+            orders: HashMap::new(),
+            build_order: 1,
+            inputs: vec![],
+        }
+    }
+}
+
+pub const USAGE: &str = "generate [--merge] [--format $fmt] \
+    [--encoding lossy|latin1|cp1252] [--config $config] [--dry-run $n] [--jobs $n] [--memory-budget $MiB] [--json] \
+    [--since $date] [--until $date] [--only $nick,...] [--exclude $nick,...] [--min-lines $n] [--no-actions] \
+    [--bucket year|month] [--order $n|auto] \
+    ($file|$dir|$glob|-)+";
+
+/// Value of option `opt`, the next argument
+fn opt_value<'a, I>(opt: &str, args: &mut I) -> Fallible<&'a String>
+where
+    I: Iterator<Item = &'a String>,
+{
+    args.next()
+        .ok_or_else(|| format!("option {} expects a value", opt).into())
+}
+
+/// Numeric value of option `opt`
+fn opt_number<'a, I>(opt: &str, args: &mut I) -> Fallible<usize>
+where
+    I: Iterator<Item = &'a String>,
+{
+    let n = opt_value(opt, args)?;
+    n.parse()
+        .map_err(|_| format!("{} expects a number, not {:?}", opt, n).into())
+}
+
+impl GenerateOpts {
+    pub fn parse(args: &[String]) -> Fallible<Self> {
+        let mut opts = GenerateOpts::default();
+        let mut args = args.iter();
+        while let Some(a) = args.next() {
+            match a.as_str() {
+                "--merge" => opts.merge = true,
+                "--no-actions" => opts.filter.no_actions = true,
+                "--json" => opts.json = true,
+                "--format" => opts.format = opt_value(a, &mut args)?.clone(),
+                "--encoding" => {
+                    opts.encoding = log_parse::Encoding::from_name(opt_value(a, &mut args)?)?
+                }
+                "--config" => opts.config = Some(opt_value(a, &mut args)?.clone()),
+                "--dry-run" => opts.dry_run = Some(opt_number(a, &mut args)?),
+                "--jobs" => opts.jobs = opt_number(a, &mut args)?.max(1),
+                "--since" => opts.filter.since = Some(parse_date(a, opt_value(a, &mut args)?)?),
+                "--until" => opts.filter.until = Some(parse_date(a, opt_value(a, &mut args)?)?),
+                "--only" => opts.filter.only = Some(parse_nicks(opt_value(a, &mut args)?)),
+                "--exclude" => opts.filter.exclude = parse_nicks(opt_value(a, &mut args)?),
+                "--min-lines" => opts.min_lines = opt_number(a, &mut args)?,
+                "--order" => opts.order = Some(Order::from_name(opt_value(a, &mut args)?)?),
+                "--bucket" => opts.bucket = Some(Bucket::from_name(opt_value(a, &mut args)?)?),
+                "--memory-budget" => {
+                    opts.memory_budget = Some(opt_number(a, &mut args)?.max(1) << 20)
+                }
+                s if s.starts_with("--") => {
+                    return Err(format!("unknown option {:?} for generate", s).into())
+                }
+                _ => opts.inputs.push(a.clone()),
+            }
+        }
+        if opts.inputs.is_empty() {
+            return Err("generate expects at least one file, directory or glob".into());
+        }
+        if let (Some(since), Some(until)) = (&opts.filter.since, &opts.filter.until) {
+            if since > until {
+                return Err(format!("--since {} is after --until {}", since, until).into());
+            }
+        }
+        Ok(opts)
+    }
+
+    /// The options that leave entries out, empty if there are none. Inputs
+    /// are recorded as ingested with it, and read again under another one.
+    fn filter_record(&self) -> String {
+        let f = &self.filter;
+        let mut opts = vec![];
+        if let Some(ref since) = f.since {
+            opts.push(format!("--since {}", since));
+        }
+        if let Some(ref until) = f.until {
+            opts.push(format!("--until {}", until));
+        }
+        if let Some(ref only) = f.only {
+            opts.push(format!("--only {}", join_nicks(only)));
+        }
+        if !f.exclude.is_empty() {
+            opts.push(format!("--exclude {}", join_nicks(&f.exclude)));
+        }
+        if f.no_actions {
+            opts.push("--no-actions".to_string());
+        }
+        if self.min_lines > 0 {
+            opts.push(format!("--min-lines {}", self.min_lines));
+        }
+        opts.join(" ")
+    }
+
+    /// Order of the chain `name` (a nick, or `nick@period`) built from `lines` lines
+    fn order_for(&self, name: &str, lines: usize) -> usize {
+        let nick = nick_file::split_period(name).0;
+        match (self.orders.get(nick), self.order) {
+            (Some(&order), _) => order,
+            (None, Some(Order::Fixed(order))) => order,
+            (None, Some(Order::Auto)) => auto_order(lines),
+            (None, None) => 1,
+        }
+    }
+}
+
+/// Expand the inputs given on the command line (globs, directories, `-`)
+/// into the list of files to parse
+fn input_files(inputs: &[String]) -> Fallible<Vec<path::PathBuf>> {
+    let mut files = vec![];
+    for i in inputs.iter() {
+        let p = path::Path::new(i);
+        if !p.exists() && i.contains(['*', '?', '[']) {
+            let mut matched = false;
+            for m in glob::glob(i)? {
+                files.extend(log_parse::input_files(&m?)?);
+                matched = true;
+            }
+            if !matched {
+                return Err(format!("no file matches {:?}", i).into());
+            }
+        } else {
+            files.extend(log_parse::input_files(p)?);
+        }
+    }
+    // a file given twice would be counted twice
+    let mut seen = HashSet::new();
+    files.retain(|f| seen.insert(f.clone()));
+    Ok(files)
+}
+
+/// Spills the chains of a worker to disk when their estimated size
+/// goes over `limit`
+struct Budget<'a> {
+    spill: &'a spill::Spill,
+    limit: usize,
+    used: usize,
+}
+
+impl<'a> Budget<'a> {
+    /// Account for `n` bytes taken by what was fed into `chains`
+    fn add(
+        &mut self,
+        n: usize,
+        chains: &mut HashMap<String, raw_chain::RawChainPair>,
+    ) -> Fallible<()> {
+        self.used += n;
+        if self.used > self.limit {
+            self.spill.spill(chains)?;
+            self.used = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Feed `msg` into the chain `name` (a nick, or `nick@period`) of `res`
+fn feed(
+    res: &mut WorkerResult,
+    name: String,
+    msg: &str,
+    order: usize,
+    budget: &mut Option<Budget>,
+) -> Fallible<()> {
+    let added = res
+        .chains
+        .entry(name.clone())
+        .or_insert_with(|| raw_chain::RawChainPair::empty(order))
+        .feed_str(msg);
+    *res.lines.entry(name).or_insert(0) += 1;
+    if let Some(ref mut b) = budget {
+        b.add(added, &mut res.chains)?;
+    }
+    Ok(())
+}
+
+/// Number of records read between two progress updates
+const PROGRESS_RECORDS: usize = 1024;
+
+/// Feed the entries of file `s` into the chains of `res`, but for its
+/// first `resume` records
+fn read_file(
+    s: &path::Path,
+    format: Box<dyn log_parse::LogFormat>,
+    resume: usize,
+    opts: &GenerateOpts,
+    res: &mut WorkerResult,
+    budget: &mut Option<Budget>,
+    progress: &progress::Progress,
+) -> Fallible<log_parse::Stats> {
+    info!("parse {:?} as {}", s, format.name());
+    let mut parser = log_parse::parse_file(s, format, opts.encoding)?;
+    let mut filtered = 0;
+    // what was already reported to `progress`
+    let (mut bytes, mut lines) = (0, 0);
+    for n in 1.. {
+        match parser.next_entry() {
+            log_parse::ParseRes::Skip => (),
+            log_parse::ParseRes::Done => break,
+            log_parse::ParseRes::Yield(_) if n <= resume => (),
+            log_parse::ParseRes::Yield(mut record) => {
+                //println!("parsed record {:?}", &record);
+                record.nick = opts.aliases.canonical(&record.nick);
+                if !opts.filter.accepts(&record) {
+                    filtered += 1;
+                } else {
+                    if let Some(period) = opts.bucket.and_then(|b| b.period(&record.date)) {
+                        let name = format!("{}{}{}", record.nick, nick_file::PERIOD_SEP, period);
+                        feed(res, name, record.msg, opts.build_order, budget)?;
+                    }
+                    feed(res, record.nick, record.msg, opts.build_order, budget)?;
+                }
+            }
+        }
+        if n % PROGRESS_RECORDS == 0 {
+            progress.add(parser.bytes() - bytes, (parser.line() - lines) as u64);
+            bytes = parser.bytes();
+            lines = parser.line();
+        }
+    }
+    progress.add(parser.bytes() - bytes, (parser.line() - lines) as u64);
+    if let Some(e) = parser.take_error() {
+        return Err(format!("error reading {:?} at line {}: {}", s, parser.line() + 1, e).into());
+    }
+    let mut stats = parser.stats();
+    stats.filtered = filtered;
+    stats.resumed = resume.min(stats.entries + stats.skipped);
+    Ok(stats)
+}
+
+/// Format to parse `file` with, detecting it if asked to
+fn format_for(
+    file: &path::Path,
+    opts: &GenerateOpts,
+    config: &config::Config,
+) -> Fallible<Box<dyn log_parse::LogFormat>> {
+    if opts.format == "auto" {
+        log_parse::detect_format(file, &config.formats)
+    } else {
+        log_parse::format_by_name(&opts.format, &config.formats)
+    }
+}
+
+/// A file to parse, with the name of its format
+type Input = (path::PathBuf, String);
+
+/// Files to parse and their formats. Formats are all chosen before any file
+/// is parsed, so that a file whose format cannot be detected fails the run
+/// before the others are read.
+fn inputs_with_formats(opts: &GenerateOpts, config: &config::Config) -> Fallible<Vec<Input>> {
+    let mut inputs = vec![];
+    for file in input_files(&opts.inputs)? {
+        let format = format_for(&file, opts, config)?.name().to_string();
+        inputs.push((file, format));
+    }
+    Ok(inputs)
+}
+
+/// Print the first `n` entries parsed from the input to `out`
+fn dry_run(
+    opts: &GenerateOpts,
+    config: &config::Config,
+    n: usize,
+    out: &mut dyn io::Write,
+) -> Fallible<()> {
+    let mut count = 0;
+    for file in input_files(&opts.inputs)? {
+        let format = format_for(&file, opts, config)?;
+        writeln!(out, "parse {:?} as {}", file, format.name())?;
+        let mut parser = log_parse::parse_file(&file, format, opts.encoding)?;
+        loop {
+            match parser.next_entry() {
+                log_parse::ParseRes::Skip => (),
+                log_parse::ParseRes::Done => break,
+                log_parse::ParseRes::Yield(mut e) => {
+                    e.nick = opts.aliases.canonical(&e.nick);
+                    if !opts.filter.accepts(&e) {
+                        continue;
+                    }
+                    if count >= n {
+                        return Ok(());
+                    }
+                    writeln!(
+                        out,
+                        "date={:?} time={:?} kind={:?} nick={:?} msg={:?}",
+                        e.date, e.time, e.kind, e.nick, e.msg
+                    )?;
+                    count += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What was read from one input file
+struct Ingested {
+    key: Option<(path::PathBuf, u64)>, // for the record of sources, `None` for stdin
+    stats: log_parse::Stats,
+}
+
+/// Parse the file of `input` into the chains of `res`, unless it was
+/// already ingested. Of a file that grew since, only the new records are fed.
+fn ingest_file(
+    (file, format): &Input,
+    opts: &GenerateOpts,
+    config: &config::Config,
+    sources: &sources::Sources,
+    res: &mut WorkerResult,
+    budget: &mut Option<Budget>,
+    progress: &progress::Progress,
+) -> Fallible<Option<Ingested>> {
+    // stdin cannot be recorded as ingested
+    let key = if log_parse::is_stdin(file) {
+        None
+    } else {
+        Some(sources::key(file)?)
+    };
+    let mut resume = 0;
+    if let Some((ref source, size)) = key {
+        let filter = opts.filter_record();
+        match sources.get(source) {
+            Some(old) if old.filter != filter => {
+                info!(
+                    "{:?} was ingested with other filters ({:?}), reading it again",
+                    source, old.filter
+                );
+            }
+            Some(old) if old.size == size => {
+                info!("{:?} was already ingested, skipping", source);
+                progress.add(size, 0);
+                return Ok(None);
+            }
+            Some(old) if old.size > size => {
+                return Err(format!(
+                    "{:?} was ingested when it had {} bytes but now has {}, \
+                     it cannot be merged again",
+                    source, old.size, size
+                )
+                .into())
+            }
+            Some(old) => {
+                info!(
+                    "{:?} grew since it was ingested, reading past {} records",
+                    source, old.records
+                );
+                resume = old.records;
+            }
+            None => (),
+        }
+    }
+
+    // fresh format for each file, they can hold per-file state, so a file
+    // that grew is parsed again from its start
+    let format = log_parse::format_by_name(format, &config.formats)?;
+    let stats = read_file(file, format, resume, opts, res, budget, progress)?;
+    if stats.malformed > 0 {
+        info!(
+            "{:?}: {} lines were not valid UTF-8 (decoded as {:?}), first ones: {:?}",
+            file, stats.malformed, opts.encoding, stats.malformed_lines
+        );
+    }
+    Ok(Some(Ingested { key, stats }))
+}
+
+/// Chains built by a worker thread (empty if they were spilled),
+/// and the files it read
+struct WorkerResult {
+    chains: HashMap<String, raw_chain::RawChainPair>,
+    lines: HashMap<String, usize>, // entries fed into the chain of each nick
+    ingested: Vec<Ingested>,
+}
+
+/// Parse files from `queue` until it is empty. With a `budget`,
+/// all chains end up spilled to disk.
+fn worker(
+    queue: &Mutex<VecDeque<Input>>,
+    opts: &GenerateOpts,
+    config: &config::Config,
+    sources: &sources::Sources,
+    mut budget: Option<Budget>,
+    progress: &progress::Progress,
+) -> Fallible<WorkerResult> {
+    let mut res = WorkerResult {
+        chains: HashMap::new(),
+        lines: HashMap::new(),
+        ingested: vec![],
+    };
+    loop {
+        let input = match queue.lock().unwrap().pop_front() {
+            Some(i) => i,
+            None => break,
+        };
+        let ingested = ingest_file(
+            &input,
+            opts,
+            config,
+            sources,
+            &mut res,
+            &mut budget,
+            progress,
+        )?;
+        res.ingested.extend(ingested);
+    }
+    if let Some(b) = budget {
+        b.spill.spill(&mut res.chains)?;
+    }
+    Ok(res)
+}
+
+/// Parse `files` on `opts.jobs` threads, sharing the memory budget if
+/// there is a `spill` directory
+fn run_workers(
+    files: Vec<Input>,
+    opts: &GenerateOpts,
+    config: &config::Config,
+    sources: &sources::Sources,
+    spill: Option<&spill::Spill>,
+    progress: &progress::Progress,
+) -> Fallible<Vec<WorkerResult>> {
+    let jobs = opts.jobs.min(files.len()).max(1);
+    let queue = Mutex::new(files.into_iter().collect::<VecDeque<_>>());
+    let budget = || {
+        spill.map(|spill| Budget {
+            spill,
+            limit: opts.memory_budget.unwrap_or(0) / jobs,
+            used: 0,
+        })
+    };
+    // errors are not `Send`, workers return them as strings
+    let results: Vec<Result<WorkerResult, String>> = thread::scope(|s| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                let budget = budget();
+                let queue = &queue;
+                s.spawn(move || {
+                    worker(queue, opts, config, sources, budget, progress)
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| {
+                w.join()
+                    .unwrap_or_else(|_| Err("worker panicked".to_string()))
+            })
+            .collect()
+    });
+    results
+        .into_iter()
+        .map(|r| r.map_err(|e| e.into()))
+        .collect()
+}
+
+/// Add the chains of `from` to `into`. Transition counts are summed, so the
+/// result does not depend on how files were split between workers.
+fn merge_chains(
+    into: &mut HashMap<String, raw_chain::RawChainPair>,
+    from: HashMap<String, raw_chain::RawChainPair>,
+) -> Fallible<()> {
+    for (nick, c) in from {
+        match into.entry(nick) {
+            Entry::Occupied(mut e) => e.get_mut().merge(&c)?,
+            Entry::Vacant(e) => {
+                e.insert(c);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where to save the chain of `nick`, if it is to be saved
+fn output_path(data_dir: &path::Path, nick: &str) -> Option<path::PathBuf> {
+    if nick.trim() == "" {
+        return None;
+    }
+    match path_for_nick(data_dir, nick) {
+        Ok(p) => Some(p),
+        Err(e) => {
+            info!("skip nick {:?}: {}", nick, e);
+            None
+        }
+    }
+}
+
+/// Chains written by a run, kept apart until all of them are written, so
+/// that a run failing midway leaves the chains and their record of sources
+/// as they were
+struct Staging {
+    dir: path::PathBuf,
+    chains: Vec<(path::PathBuf, path::PathBuf)>, // staged path, path in the data dir
+}
+
+impl Staging {
+    fn new(data_dir: &path::Path) -> Fallible<Self> {
+        let dir = data_dir.join(format!(".staging-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(Staging {
+            dir,
+            chains: vec![],
+        })
+    }
+
+    /// Where to write the chain that goes to `p`
+    fn path(&mut self, p: &path::Path) -> path::PathBuf {
+        let staged = self.dir.join(p.file_name().unwrap());
+        self.chains.push((staged.clone(), p.to_path_buf()));
+        staged
+    }
+
+    /// Move the staged chains and their reverse chains into the data dir
+    fn commit(self) -> Fallible<()> {
+        for (staged, p) in self.chains.iter() {
+            fs::rename(staged, p)?;
+            fs::rename(reverse_path(staged), reverse_path(p))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Highest order of the chains stored in `data_dir`, 1 if there are none
+fn existing_order(data_dir: &path::Path) -> Fallible<usize> {
+    let mut order = 1;
+    for p in fs::read_dir(data_dir)? {
+        let path = p?.path();
+        if path.extension() != Some(std::ffi::OsStr::new("bin")) {
+            continue;
+        }
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        // skips reverse chains, which have the order of their chain
+        if nick_file::decode_chain(&stem).is_some() {
+            order = order.max(crate::chain_order(&path)?);
+        }
+    }
+    Ok(order)
+}
+
+/// What was generated for one nick
+#[derive(Serialize)]
+struct NickSummary {
+    nick: String,
+    lines: usize,      // read in this run
+    order: usize,      // of the saved chain
+    vocabulary: usize, // distinct words in the chain
+    file_size: u64,    // of the chain and its reverse chain
+}
+
+/// What a `generate` run did, printed at the end
+#[derive(Serialize)]
+struct Summary {
+    lines: usize,
+    entries: usize,
+    skipped: usize,
+    malformed: usize,
+    filtered: usize, // notices, system lines, and entries left out by `--since`, etc.
+    resumed: usize,  // records of grown files that were ingested by an earlier run
+    seconds: f64,
+    small_nicks: usize, // nicks left out by `--min-lines`
+    nicks: Vec<NickSummary>,
+    #[serde(skip)]
+    lines_by_nick: HashMap<String, usize>,
+}
+
+impl Summary {
+    fn print(&mut self, opts: &GenerateOpts) -> Fallible<()> {
+        self.nicks
+            .sort_by(|a, b| b.lines.cmp(&a.lines).then_with(|| a.nick.cmp(&b.nick)));
+        if opts.json {
+            println!("{}", serde_json::to_string(self)?);
+            return Ok(());
+        }
+        println!(
+            "read {} lines in {:.1}s: {} entries, {} skipped, {} not valid UTF-8, {} filtered out",
+            self.lines, self.seconds, self.entries, self.skipped, self.malformed, self.filtered
+        );
+        if self.resumed > 0 {
+            println!(
+                "{} records were ingested by an earlier run and left out",
+                self.resumed
+            );
+        }
+        if self.small_nicks > 0 {
+            println!(
+                "{} nicks with fewer than {} lines got no chain",
+                self.small_nicks, opts.min_lines
+            );
+        }
+        println!(
+            "{:>10} {:>5} {:>10} {:>10}  nick",
+            "lines", "order", "vocabulary", "bytes"
+        );
+        for n in self.nicks.iter() {
+            println!(
+                "{:>10} {:>5} {:>10} {:>10}  {}",
+                n.lines, n.order, n.vocabulary, n.file_size, n.nick
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Stage `raw`, the chains built for `nick`, merging them into the existing
+/// ones if asked to, and record them in `summary`
+fn save_nick(
+    opts: &GenerateOpts,
+    data_dir: &path::Path,
+    nick: &str,
+    raw: raw_chain::RawChainPair,
+    staging: &mut Staging,
+    summary: &mut Summary,
+) -> Fallible<()> {
+    let path = match output_path(data_dir, nick) {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let lines = summary.lines_by_nick.get(nick).cloned().unwrap_or(0);
+    let existing = opts.merge && path.exists();
+    // an existing chain keeps being updated, whatever the number of new lines
+    if lines < opts.min_lines && !existing {
+        summary.small_nicks += 1;
+        return Ok(());
+    }
+    // an existing chain keeps its order, which may be lower than what was built
+    let order = if existing {
+        crate::chain_order(&path)?
+    } else {
+        opts.order_for(nick, lines)
+    };
+    // `build_order` covers the existing chains, unless one changed meanwhile
+    if order > raw.fwd.order {
+        return Err(format!(
+            "{:?} has order {}, but its new lines were built with order {}",
+            path, order, raw.fwd.order
+        )
+        .into());
+    }
+    let mut raw = raw.reduce(order);
+    if existing {
+        raw.merge(&raw_chain::RawChainPair::load(&path)?)?;
+    }
+    //println!("save for nick `{}` in {:?}", nick, path);
+    let path = staging.path(&path);
+    raw.save(&path)?;
+    summary.nicks.push(NickSummary {
+        nick: nick.to_string(),
+        lines,
+        order,
+        vocabulary: raw.fwd.vocabulary(),
+        file_size: fs::metadata(&path)?.len() + fs::metadata(reverse_path(&path))?.len(),
+    });
+    Ok(())
+}
+
+/// Merge spilled chains, one nick at a time, and save them
+fn save_spilled(
+    opts: &GenerateOpts,
+    data_dir: &path::Path,
+    spill: &spill::Spill,
+    staging: &mut Staging,
+    summary: &mut Summary,
+) -> Fallible<()> {
+    for (nick, files) in spill.files()? {
+        let mut raw: Option<raw_chain::RawChainPair> = None;
+        for f in files {
+            let c = raw_chain::RawChainPair::load(&f)?;
+            match raw {
+                Some(ref mut raw) => raw.merge(&c)?,
+                None => raw = Some(c),
+            }
+        }
+        if let Some(raw) = raw {
+            save_nick(opts, data_dir, &nick, raw, staging, summary)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn generate(opts: &GenerateOpts) -> Fallible<()> {
+    let config = match opts.config {
+        Some(ref p) => config::Config::load(path::Path::new(p))?,
+        None => config::Config::load_or_default(path::Path::new(config::DEFAULT_PATH))?,
+    };
+    if opts.json {
+        // only the summary on stdout
+        crate::INFO_TO_STDERR.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    let mut opts = opts.clone();
+    opts.aliases = aliases::Aliases::from_config(&config)?;
+    opts.filter.resolve_nicks(&opts.aliases);
+    opts.order = Some(opts.order.unwrap_or(Order::Fixed(config.chain_order())));
+    opts.orders = opts.aliases.canonical_keys(&config.orders);
+    // chains are built with the highest order needed, and reduced when saved
+    opts.build_order = opts.orders.values().cloned().fold(
+        match opts.order {
+            Some(Order::Fixed(order)) => order,
+            _ => AUTO_MAX_ORDER,
+        },
+        usize::max,
+    );
+    if let Some(n) = opts.dry_run {
+        return dry_run(&opts, &config, n, &mut io::stdout());
+    }
+    let data_dir: &path::Path = &config.data_dir;
+    info!("create dir {:?}", data_dir);
+    fs::create_dir_all(data_dir)?;
+    // chains of older runs may have been named with another mapping or aliases
+    opts.aliases.rename_chains(data_dir)?;
+    if opts.merge {
+        // merged chains keep their order, so they cannot be built with a
+        // lower one; checked now rather than after some chains are saved
+        opts.build_order = opts.build_order.max(existing_order(data_dir)?);
+    }
+    let opts = &opts;
+
+    // without `--merge`, chains are replaced and their record with them
+    let mut sources = if opts.merge {
+        sources::Sources::load(data_dir)?
+    } else {
+        sources::Sources::new(data_dir)
+    };
+    let files = inputs_with_formats(opts, &config)?;
+    let mut total_bytes = Some(0);
+    for (f, _) in files.iter() {
+        total_bytes = match total_bytes {
+            Some(n) if !log_parse::is_stdin(f) => Some(n + fs::metadata(f)?.len()),
+            _ => None,
+        };
+    }
+    let progress = progress::Progress::new(total_bytes);
+    let spill = match opts.memory_budget {
+        Some(_) => Some(spill::Spill::new(data_dir)?),
+        None => None,
+    };
+    let results = run_workers(files, opts, &config, &sources, spill.as_ref(), &progress)?;
+    progress.finish();
+
+    let filter = opts.filter_record();
+    let mut total = log_parse::Stats::default();
+    let mut chains = HashMap::new();
+    let mut lines_by_nick = HashMap::new();
+    for r in results {
+        merge_chains(&mut chains, r.chains)?;
+        for (nick, n) in r.lines {
+            *lines_by_nick.entry(nick).or_insert(0) += n;
+        }
+        for i in r.ingested {
+            total.add(&i.stats);
+            if let Some((source, size)) = i.key {
+                sources.add(source, size, i.stats.entries + i.stats.skipped, &filter);
+            }
+        }
+    }
+
+    let mut summary = Summary {
+        lines: total.lines,
+        entries: total.entries,
+        skipped: total.skipped,
+        malformed: total.malformed,
+        filtered: total.filtered,
+        resumed: total.resumed,
+        seconds: 0.,
+        small_nicks: 0,
+        nicks: vec![],
+        lines_by_nick,
+    };
+    let mut staging = Staging::new(data_dir)?;
+    for (nick, raw) in chains {
+        save_nick(opts, data_dir, &nick, raw, &mut staging, &mut summary)?;
+    }
+    if let Some(spill) = spill {
+        save_spilled(opts, data_dir, &spill, &mut staging, &mut summary)?;
+        spill.remove()?;
+    }
+    staging.commit()?;
+    sources.save()?;
+    summary.seconds = progress.elapsed().as_secs_f64();
+    summary.print(opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::test_dir};
+
+    const LINES: &[(&str, &str)] = &[
+        ("alice", "hello there"),
+        ("bob", "hi alice"),
+        ("alice", "how are you"),
+        ("bob", "fine thanks and you"),
+        ("alice", "hello bob how are you"),
+    ];
+
+    /// Chains of order 2 fed with `lines`, by nick
+    fn chains_of(lines: &[(&str, &str)]) -> HashMap<String, raw_chain::RawChainPair> {
+        let mut chains = HashMap::new();
+        for &(nick, msg) in lines {
+            chains
+                .entry(nick.to_string())
+                .or_insert_with(|| raw_chain::RawChainPair::empty(2))
+                .feed_str(msg);
+        }
+        chains
+    }
+
+    fn raw(c: &raw_chain::RawChainPair) -> (&raw_chain::RawChain, &raw_chain::RawChain) {
+        (&c.fwd, &c.rev)
+    }
+
+    fn parse(args: &str) -> Fallible<GenerateOpts> {
+        let args: Vec<String> = args.split_whitespace().map(|a| a.to_string()).collect();
+        GenerateOpts::parse(&args)
+    }
+
+    #[test]
+    fn options() {
+        let opts = parse("--order auto --bucket month --jobs 0 --memory-budget 2 a.log -").unwrap();
+        assert!(matches!(opts.order, Some(Order::Auto)));
+        assert!(matches!(opts.bucket, Some(Bucket::Month)));
+        assert_eq!((opts.jobs, opts.memory_budget), (1, Some(2 << 20)));
+        assert_eq!(opts.inputs, ["a.log", "-"]);
+        assert!(matches!(
+            parse("--order 2 a.log").unwrap().order,
+            Some(Order::Fixed(2))
+        ));
+        for (args, error) in &[
+            ("", "generate expects at least one file"),
+            ("--merge", "generate expects at least one file"),
+            ("--frobnicate a.log", "unknown option \"--frobnicate\""),
+            ("a.log --format", "option --format expects a value"),
+            ("--jobs many a.log", "--jobs expects a number"),
+            ("--order 0 a.log", "--order expects auto or a number"),
+            ("--bucket week a.log", "unknown bucket \"week\""),
+            ("--since 2021 --until 2020 a.log", "--since 2021 is after"),
+        ] {
+            let e = parse(args).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{}: {}", args, e);
+        }
+    }
+
+    #[test]
+    fn orders() {
+        assert!(matches!(Order::from_name("auto"), Ok(Order::Auto)));
+        assert!(matches!(Order::from_name("1"), Ok(Order::Fixed(1))));
+        let max = config::MAX_ORDER;
+        assert!(matches!(Order::from_name(&max.to_string()), Ok(Order::Fixed(n)) if n == max));
+        for s in &["0", &(max + 1).to_string(), "-1", "Auto", ""] {
+            assert!(Order::from_name(s).is_err(), "{}", s);
+        }
+        for &(lines, order) in &[(0, 1), (1_999, 1), (2_000, 2), (19_999, 2), (20_000, 3)] {
+            assert_eq!(auto_order(lines), order, "{}", lines);
+        }
+
+        let mut opts = GenerateOpts::default();
+        assert_eq!(opts.order_for("alice", 50_000), 1);
+        opts.orders.insert("alice".to_string(), 2);
+        opts.order = Some(Order::Fixed(3));
+        assert_eq!(opts.order_for("alice", 10), 2);
+        assert_eq!(opts.order_for("alice@2020", 10), 2);
+        assert_eq!(opts.order_for("bob", 10), 3);
+        opts.order = Some(Order::Auto);
+        assert_eq!(opts.order_for("alice", 50_000), 2);
+        assert_eq!(opts.order_for("bob@2020-02", 50_000), 3);
+        assert_eq!(opts.order_for("bob", 10), 1);
+    }
+
+    #[test]
+    fn summary_json() {
+        let summary = Summary {
+            lines: 10,
+            entries: 8,
+            skipped: 1,
+            malformed: 1,
+            filtered: 2,
+            resumed: 0,
+            seconds: 1.5,
+            small_nicks: 0,
+            nicks: vec![NickSummary {
+                nick: "alice".to_string(),
+                lines: 6,
+                order: 2,
+                vocabulary: 12,
+                file_size: 345,
+            }],
+            lines_by_nick: vec![("alice".to_string(), 6)].into_iter().collect(),
+        };
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "lines": 10,
+                "entries": 8,
+                "skipped": 1,
+                "malformed": 1,
+                "filtered": 2,
+                "resumed": 0,
+                "seconds": 1.5,
+                "small_nicks": 0,
+                "nicks": [
+                    {"nick": "alice", "lines": 6, "order": 2, "vocabulary": 12, "file_size": 345}
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn filter_record() {
+        assert_eq!(parse("--merge a.log").unwrap().filter_record(), "");
+        let opts = parse(
+            "--merge --min-lines 10 --no-actions --exclude Bob,@alice --until 2021 \
+             --since 2020-06 a.log",
+        )
+        .unwrap();
+        assert_eq!(
+            opts.filter_record(),
+            "--since 2020-06 --until 2021 --exclude alice,bob --no-actions --min-lines 10"
+        );
+        let opts = parse("--only carol,alice a.log").unwrap();
+        assert_eq!(opts.filter_record(), "--only alice,carol");
+    }
+
+    #[test]
+    fn dates() {
+        for d in &["2020", "2020-02", "2020-02-29"] {
+            assert_eq!(parse_date("--since", d).unwrap(), *d);
+        }
+        for d in &[
+            "20",
+            "2020-2",
+            "2020/02",
+            "2020-02-3x",
+            "2020-02-29 10:00",
+            "",
+        ] {
+            let e = parse_date("--since", d).unwrap_err().to_string();
+            assert!(e.starts_with("--since expects"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn periods() {
+        for &(date, year, month) in &[
+            ("2019-12-31", "2019", "2019-12"),
+            ("2020-01-01", "2020", "2020-01"),
+            ("2020-02-29", "2020", "2020-02"),
+            ("2020-03-01", "2020", "2020-03"),
+        ] {
+            assert_eq!(Bucket::Year.period(date), Some(year), "{}", date);
+            assert_eq!(Bucket::Month.period(date), Some(month), "{}", date);
+        }
+        // entries without a (readable) date go in no period
+        for date in &["", "2020", "10:00:00", "20-02-2020", "2020/02/29"] {
+            assert_eq!(Bucket::Month.period(date), None, "{}", date);
+        }
+        assert_eq!(Bucket::Year.period("2020"), Some("2020"));
+        assert_eq!(Bucket::Year.period(""), None);
+        assert_eq!(Bucket::Year.period("10:00:00"), None);
+    }
+
+    #[test]
+    fn filter_dates() {
+        let entry = |date: &'static str, kind| log_parse::Entry {
+            date: date.into(),
+            time: "10:00:00",
+            nick: "alice".into(),
+            msg: "hi",
+            kind,
+        };
+        let accepts = |since: Option<&str>, until: Option<&str>, date| {
+            let f = Filter {
+                since: since.map(|s| s.to_string()),
+                until: until.map(|s| s.to_string()),
+                ..Filter::default()
+            };
+            f.accepts(&entry(date, log_parse::LineKind::Message))
+        };
+        for &(bound, before, first, last, after) in &[
+            (
+                "2020",
+                "2019-12-31",
+                "2020-01-01",
+                "2020-12-31",
+                "2021-01-01",
+            ),
+            (
+                "2020-02",
+                "2020-01-31",
+                "2020-02-01",
+                "2020-02-29",
+                "2020-03-01",
+            ),
+            (
+                "2020-02-10",
+                "2020-02-09",
+                "2020-02-10",
+                "2020-02-10",
+                "2020-02-11",
+            ),
+        ] {
+            assert!(!accepts(Some(bound), None, before), "since {}", bound);
+            assert!(accepts(Some(bound), None, first), "since {}", bound);
+            assert!(accepts(Some(bound), None, after), "since {}", bound);
+            assert!(accepts(None, Some(bound), before), "until {}", bound);
+            assert!(accepts(None, Some(bound), last), "until {}", bound);
+            assert!(!accepts(None, Some(bound), after), "until {}", bound);
+            assert!(accepts(Some(bound), Some(bound), last), "{}", bound);
+        }
+        // without a date, entries are only kept when there is no range
+        assert!(accepts(None, None, ""));
+        assert!(!accepts(Some("2020"), None, ""));
+        assert!(!accepts(None, Some("2020"), ""));
+
+        let f = Filter {
+            no_actions: true,
+            ..Filter::default()
+        };
+        assert!(f.accepts(&entry("", log_parse::LineKind::Message)));
+        assert!(!f.accepts(&entry("", log_parse::LineKind::Action)));
+        assert!(!Filter::default().accepts(&entry("", log_parse::LineKind::Notice)));
+    }
+
+    #[test]
+    fn budget_spills_when_over_limit() {
+        let data_dir = test_dir("budget");
+        let spill = spill::Spill::new(&data_dir).unwrap();
+        let line_size = raw_chain::RawChainPair::empty(2).feed_str("hello there");
+        let mut budget = Some(Budget {
+            spill: &spill,
+            limit: line_size + 1,
+            used: 0,
+        });
+        let mut res = worker_result();
+        // repeated lines take no more memory, and never spill
+        for _ in 0..100 {
+            feed(&mut res, "alice".into(), "hello there", 2, &mut budget).unwrap();
+        }
+        assert_eq!(budget.as_ref().unwrap().used, line_size);
+        assert_eq!(res.chains.len(), 1);
+        assert!(spill.files().unwrap().is_empty());
+        // a new line goes over the limit
+        feed(&mut res, "bob".into(), "hi", 2, &mut budget).unwrap();
+        assert_eq!(budget.as_ref().unwrap().used, 0);
+        assert!(res.chains.is_empty());
+        let files = spill.files().unwrap();
+        assert_eq!(files["alice"].len(), 1);
+        assert_eq!(files["bob"].len(), 1);
+        spill.remove().unwrap();
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    fn worker_result() -> WorkerResult {
+        WorkerResult {
+            chains: HashMap::new(),
+            lines: HashMap::new(),
+            ingested: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ingest_grown_file() {
+        let dir = test_dir("ingest");
+        let log = dir.join("w.log");
+        let first = "2020-02-02 10:00:00\talice\thello there\n\
+                     2020-02-02 10:00:01\t-->\tbob joined\n";
+        fs::write(&log, first).unwrap();
+        let opts = GenerateOpts {
+            format: "weechat".into(),
+            build_order: 2,
+            ..GenerateOpts::default()
+        };
+        let config = config::Config::default();
+        let progress = progress::Progress::new(None);
+        let mut sources = sources::Sources::new(&dir);
+        let input = (log.clone(), "weechat".to_string());
+        let ingest = |sources: &sources::Sources, res: &mut WorkerResult| {
+            ingest_file(&input, &opts, &config, sources, res, &mut None, &progress)
+        };
+
+        let mut res = worker_result();
+        let i = ingest(&sources, &mut res).unwrap().unwrap();
+        let (source, size) = i.key.unwrap();
+        assert_eq!(size, first.len() as u64);
+        assert_eq!(i.stats.entries + i.stats.skipped, 2);
+        sources.add(source.clone(), size, 2, "");
+        // unchanged, the file is skipped
+        assert!(ingest(&sources, &mut res).unwrap().is_none());
+
+        // grown, only the new records are fed
+        let grown = format!("{}2020-02-02 10:00:02\tbob\thi alice\n", first);
+        fs::write(&log, &grown).unwrap();
+        let mut res = worker_result();
+        let i = ingest(&sources, &mut res).unwrap().unwrap();
+        assert_eq!(i.stats.resumed, 2);
+        assert_eq!(i.stats.entries + i.stats.skipped, 3);
+        assert_eq!(res.lines.len(), 1);
+        assert_eq!(res.lines["bob"], 1);
+
+        // shrunk, the chains cannot be fixed
+        sources.add(source.clone(), grown.len() as u64 + 1, 3, "");
+        let e = match ingest(&sources, &mut worker_result()) {
+            Err(e) => e,
+            Ok(_) => panic!("a shrunk file was ingested"),
+        };
+        assert!(e.to_string().contains("cannot be merged again"), "{}", e);
+
+        // ingested under another filter, the file is read again whole
+        sources.add(source, grown.len() as u64, 3, "--exclude bob");
+        let mut res = worker_result();
+        let i = ingest(&sources, &mut res).unwrap().unwrap();
+        assert_eq!(i.stats.resumed, 0);
+        assert_eq!(res.lines["alice"], 1);
+        assert_eq!(res.lines["bob"], 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn formats_chosen_before_parsing() {
+        let dir = test_dir("formats");
+        let log = dir.join("w.log");
+        fs::write(&log, "2020-02-02 10:00:00\talice\thello there\n").unwrap();
+        let opts = GenerateOpts {
+            inputs: vec![dir.to_str().unwrap().into()],
+            ..GenerateOpts::default()
+        };
+        let config = config::Config::default();
+        let inputs = inputs_with_formats(&opts, &config).unwrap();
+        assert_eq!(inputs, [(log, "weechat".to_string())]);
+        // one undetectable file fails the whole run
+        fs::write(dir.join("notes.txt"), "remember the milk\n").unwrap();
+        let e = inputs_with_formats(&opts, &config).unwrap_err().to_string();
+        assert!(e.contains("notes.txt"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_runs_change_nothing() {
+        let dir = test_dir("failed-run");
+        let data_dir = dir.join("data");
+        let config = dir.join("charliebot.toml");
+        fs::write(
+            &config,
+            format!("data_dir = {:?}\n", data_dir.to_str().unwrap()),
+        )
+        .unwrap();
+        let log = dir.join("w.log");
+        let lines = "2020-02-02 10:00:00\talice\thello there\n\
+                     2020-02-02 10:00:01\tbob\thi alice\n";
+        fs::write(&log, lines).unwrap();
+        let run = || {
+            let args: Vec<String> = vec![
+                "--merge".into(),
+                "--config".into(),
+                config.to_str().unwrap().into(),
+                log.to_str().unwrap().into(),
+            ];
+            generate(&GenerateOpts::parse(&args).unwrap())
+        };
+        run().unwrap();
+        let alice = path_for_nick(&data_dir, "alice").unwrap();
+        let read = |p: &path::Path| fs::read(p).unwrap();
+        let (alice_before, sources_before) = (read(&alice), read(&data_dir.join("sources.txt")));
+
+        // the chain of bob has a valid header but cannot be read whole
+        let mut bob = crate::CHAIN_MAGIC.to_vec();
+        bob.extend(bincode::serialize(&1u64).unwrap());
+        bob.extend(b"garbage");
+        fs::write(path_for_nick(&data_dir, "bob").unwrap(), bob).unwrap();
+        fs::write(&log, format!("{}{}", lines, lines)).unwrap();
+        assert!(run().is_err());
+        assert_eq!(read(&alice), alice_before);
+        assert_eq!(read(&data_dir.join("sources.txt")), sources_before);
+        for f in fs::read_dir(&data_dir).unwrap() {
+            let name = f.unwrap().file_name();
+            assert!(
+                !name.to_string_lossy().starts_with(".staging"),
+                "{:?}",
+                name
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_with_regex_format() {
+        let dir = test_dir("dry-run");
+        let log = dir.join("bot.log");
+        fs::write(
+            &log,
+            "2020/2/29 10:00 <Alice> hello there\n\
+             2020/2/29 10:01 <***> bob joined\n\
+             29/2/2020 10:02 <bob> a date that cannot be read\n\
+             2020/3/1 10:03 <bob> hi alice\n\
+             2020/3/1 10:04 <carol> not printed\n",
+        )
+        .unwrap();
+        let mut config = config::Config::default();
+        config.formats.insert(
+            "mybot".into(),
+            config::RegexFormatConfig {
+                line: r"^(?P<date>\S+) (?P<time>\S+) <(?P<nick>[^>]+)> (?P<msg>.*)$".into(),
+                skip: Some(r"^\S+ \S+ <\*\*\*> ".into()),
+            },
+        );
+        let opts = GenerateOpts {
+            format: "mybot".into(),
+            inputs: vec![log.to_str().unwrap().into()],
+            ..GenerateOpts::default()
+        };
+        let mut out = vec![];
+        dry_run(&opts, &config, 2, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "parse {:?} as mybot\n\
+                 date=\"2020-02-29\" time=\"10:00\" kind=Message nick=\"alice\" msg=\"hello there\"\n\
+                 date=\"2020-03-01\" time=\"10:03\" kind=Message nick=\"bob\" msg=\"hi alice\"\n",
+                log
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merged_chains_equal_one_chain() {
+        let whole = chains_of(LINES);
+        let mut merged = HashMap::new();
+        for part in LINES.chunks(2) {
+            merge_chains(&mut merged, chains_of(part)).unwrap();
+        }
+        assert_eq!(merged.len(), whole.len());
+        for (nick, c) in whole.iter() {
+            assert_eq!(raw(&merged[nick]), raw(c), "{}", nick);
+        }
+    }
+}
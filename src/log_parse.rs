@@ -1,43 +1,352 @@
 /// Parse logs
-use std::io::BufRead;
+use {
+    crate::config::RegexFormatConfig,
+    std::{
+        borrow::Cow,
+        cell::Cell,
+        collections::HashMap,
+        fs,
+        io::{self, BufRead, Read},
+        path,
+        rc::Rc,
+    },
+};
+
+mod decode;
+mod input;
+mod irssi;
+mod json;
+mod regex_format;
+mod znc;
+
+pub use self::{decode::Encoding, input::is_stdin};
 
 type Fallible<T> = crate::Fallible<T>;
 
+/// What a log line is about
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LineKind {
+    Message,
+    /// `/me` actions, `msg` is what follows the nick
+    Action,
+    Notice,
+    /// Joins, parts, nick changes, errors, etc. `nick` is empty.
+    System,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Entry<'a> {
-    pub date: &'a str,
+    pub date: Cow<'a, str>, // `YYYY-MM-DD`, not always present in the line itself
     pub time: &'a str,
     pub nick: String,
     pub msg: &'a str,
+    pub kind: LineKind,
+}
+
+/// A log format, turning lines (or records) into entries
+pub trait LogFormat {
+    /// Name of the format, as given to `generate --format`
+    fn name(&self) -> &str;
+
+    /// Parse an entry from a line. Lines are given in order, so the format
+    /// can keep some state between lines.
+    fn parse_line<'a>(&mut self, line: &'a str) -> Option<Entry<'a>>;
+
+    /// Called before parsing the lines of file `_p`
+    fn start_file(&mut self, _p: &path::Path) {}
+
+    /// Read the next record from `r` into `buf` (which is empty), returning
+    /// its length like `read_line`, 0 meaning the end of input.
+    /// Records are lines by default.
+    fn read_record(&mut self, r: &mut dyn BufRead, buf: &mut String) -> io::Result<usize> {
+        r.read_line(buf)
+    }
+
+    /// Score between 0 and 1 of `_head`, the first bytes of a file, for
+    /// formats recognized without parsing records. `None` to detect the
+    /// format by parsing a sample of records.
+    fn sniff(&self, _head: &[u8]) -> Option<f64> {
+        None
+    }
+}
+
+/// Names of the known formats
+pub const FORMATS: &[&str] = &["weechat", "irssi", "znc", "matrix", "discord", "telegram"];
+
+/// Default format for `generate`, detect the format of each file
+pub const DEFAULT_FORMAT: &str = "auto";
+
+/// Number of records sampled to detect the format of a file
+const DETECT_SAMPLE: usize = 200;
+
+/// Bytes read from each input (and buffered for stdin) to detect its format
+const DETECT_BYTES: usize = 1 << 20;
+
+/// Bytes given to `LogFormat::sniff`
+const SNIFF_BYTES: usize = 64 << 10;
+
+/// Proportion of the sampled records that must give plausible entries
+/// for a format to be picked
+const DETECT_THRESHOLD: f64 = 0.25;
+
+/// Find a format by its name, among builtin formats and the `custom` ones
+/// defined in the config
+pub fn format_by_name(
+    name: &str,
+    custom: &HashMap<String, RegexFormatConfig>,
+) -> Fallible<Box<dyn LogFormat>> {
+    match name {
+        "weechat" => Ok(Box::new(Weechat)),
+        "irssi" => Ok(Box::new(irssi::Irssi::new())),
+        "znc" => Ok(Box::new(znc::Znc::new())),
+        "matrix" => Ok(Box::new(json::Json::new(json::Kind::Matrix))),
+        "discord" => Ok(Box::new(json::Json::new(json::Kind::Discord))),
+        "telegram" => Ok(Box::new(json::Json::new(json::Kind::Telegram))),
+        _ => match custom.get(name) {
+            Some(c) => Ok(Box::new(regex_format::RegexFormat::new(name, c)?)),
+            None => Err(format!(
+                "unknown log format {:?} (known: {:?}, or defined in the config: {:?})",
+                name,
+                FORMATS,
+                custom.keys().collect::<Vec<_>>()
+            )
+            .into()),
+        },
+    }
+}
+
+/// Does `e` look like a real entry? Dates and times are optional since
+/// some formats do not have them, but if present they must look right.
+fn plausible(e: &Entry) -> bool {
+    let shape = |s: &str, pat: &str| {
+        s.len() == pat.len()
+            && s.bytes().zip(pat.bytes()).all(|(c, p)| {
+                if p == b'0' {
+                    c.is_ascii_digit()
+                } else {
+                    c == p
+                }
+            })
+    };
+    let date_ok = e.date.is_empty() || shape(&e.date, "0000-00-00");
+    let time_ok = e.time.is_empty() || shape(e.time, "00:00") || shape(e.time, "00:00:00");
+    // a nick does not start with punctuation like `<`, `*` or `-`
+    let nick_ok = e.kind == LineKind::System
+        || e.nick
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || "[]\\`_^{|}".contains(c))
+            && !e.nick.contains(char::is_whitespace);
+    date_ok && time_ok && nick_ok
+}
+
+/// Proportion of the first records of `f` that `format` parses
+/// into plausible entries, or its score from sniffing the first bytes
+fn detect_score(f: &path::Path, mut format: Box<dyn LogFormat>) -> Fallible<f64> {
+    format.start_file(f);
+    let mut head = vec![];
+    input::open_sample(f, DETECT_BYTES)?
+        .take(SNIFF_BYTES as u64)
+        .read_to_end(&mut head)?;
+    if let Some(score) = format.sniff(&head) {
+        return Ok(score);
+    }
+    // a single huge line must not be read whole
+    let r = input::open_sample(f, DETECT_BYTES)?.take(DETECT_BYTES as u64);
+    let mut parser = Parser::new(r, format, Encoding::Lossy);
+    let (mut n, mut ok) = (0, 0);
+    while n < DETECT_SAMPLE {
+        match parser.next_entry() {
+            ParseRes::Done => break,
+            ParseRes::Skip => n += 1,
+            ParseRes::Yield(e) => {
+                n += 1;
+                if plausible(&e) {
+                    ok += 1
+                }
+            }
+        }
+    }
+    Ok(if n == 0 { 0. } else { ok as f64 / n as f64 })
+}
+
+/// Pick the format (builtin or `custom`) that best parses the beginning of `f`.
+/// On ties, custom formats win, then builtin formats in the order of `FORMATS`.
+pub fn detect_format(
+    f: &path::Path,
+    custom: &HashMap<String, RegexFormatConfig>,
+) -> Fallible<Box<dyn LogFormat>> {
+    let mut names: Vec<&str> = custom.keys().map(|s| s.as_str()).collect();
+    names.sort();
+    names.extend(FORMATS);
+
+    let mut best: Option<(&str, f64)> = None;
+    for name in names {
+        let score = detect_score(f, format_by_name(name, custom)?)?;
+        match best {
+            Some((_, b)) if score <= b => (),
+            _ => best = Some((name, score)),
+        }
+    }
+    match best {
+        Some((name, score)) if score >= DETECT_THRESHOLD => {
+            info!(
+                "detected format {} for {:?} ({:.0}% of sampled lines parsed)",
+                name,
+                f,
+                score * 100.
+            );
+            format_by_name(name, custom)
+        }
+        Some((name, score)) => Err(format!(
+            "cannot detect the log format of {:?}: best guess is {} with only {:.0}% of \
+             sampled lines parsed, use `--format` to choose one",
+            f,
+            name,
+            score * 100.
+        )
+        .into()),
+        None => Err(format!("cannot detect the log format of {:?}", f).into()),
+    }
 }
 
 pub struct Parser<R: BufRead> {
-    r: R,
+    r: decode::Decoder<R>,
     buf: String,
+    format: Box<dyn LogFormat>,
+    entries: usize,
+    skipped: usize,
+    error: Option<io::Error>,
+    bytes: input::ByteCount, // read from the input, before decoding
+}
+
+/// Counters about a parsed input
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub lines: usize,                // lines read
+    pub entries: usize,              // records that gave an entry
+    pub skipped: usize,              // records ignored by the format (joins, etc.)
+    pub malformed: usize,            // lines that were not valid UTF-8
+    pub malformed_lines: Vec<usize>, // the first of these lines
+    pub filtered: usize,             // entries left out by the caller
+    pub resumed: usize,              // records ingested by an earlier run, not fed again
 }
 
+impl Stats {
+    /// Add the counters of `other` (but not its line numbers)
+    pub fn add(&mut self, other: &Stats) {
+        self.lines += other.lines;
+        self.entries += other.entries;
+        self.skipped += other.skipped;
+        self.malformed += other.malformed;
+        self.filtered += other.filtered;
+        self.resumed += other.resumed;
+    }
+}
+
+/// Mode prefixes of nicks in channels: owner, admin, op, halfop, voice
+const MODE_PREFIXES: &str = "~&@%+";
+
+/// Lowercase `s`, without its mode prefix or `<nick>` brackets
 pub fn normalize_nick(s: &str) -> String {
-    s.trim().trim_matches(|c| c == '@' || c == '>').to_ascii_lowercase()
+    let s = s.trim().trim_start_matches('<');
+    let s = s.strip_suffix('>').unwrap_or(s);
+    s.trim_start_matches(|c| MODE_PREFIXES.contains(c))
+        .to_ascii_lowercase()
+}
+
+/// `date` as `YYYY-MM-DD`, from a year, month and day separated by `-`,
+/// `/` or `.` (`2020/2/29`), or not separated (`20200229`)
+fn normalize_date(date: &str) -> Option<String> {
+    let digits = |s: &str| s.bytes().all(|c| c.is_ascii_digit());
+    let fields: Vec<&str> = if date.len() == 8 && digits(date) {
+        vec![&date[..4], &date[4..6], &date[6..]]
+    } else {
+        date.split(['-', '/', '.']).collect()
+    };
+    let (y, m, d) = match fields[..] {
+        [y, m, d] if y.len() == 4 && m.len() <= 2 && d.len() <= 2 => (y, m, d),
+        _ => return None,
+    };
+    if !(digits(y) && digits(m) && digits(d)) {
+        return None;
+    }
+    let (y, m, d): (u32, u32, u32) = (y.parse().ok()?, m.parse().ok()?, d.parse().ok()?);
+    if (1..=12).contains(&m) && (1..=31).contains(&d) {
+        Some(format!("{:04}-{:02}-{:02}", y, m, d))
+    } else {
+        None
+    }
+}
+
+/// Kind, nick and message of the `<@nick> msg` and `* nick action` lines
+/// of irssi and ZNC logs, after their timestamp. Other lines (joins, modes,
+/// etc.) give `None`.
+fn message_or_action(rest: &str) -> Option<(LineKind, String, &str)> {
+    if rest.starts_with('<') {
+        // `<@nick> msg`
+        let end = rest.find('>')?;
+        let nick = normalize_nick(&rest[1..end]);
+        Some((LineKind::Message, nick, rest[end + 1..].trim()))
+    } else if let Some(rest) = rest.strip_prefix("* ") {
+        // `* nick action`
+        let mut splitter = rest.splitn(2, ' ');
+        let nick = normalize_nick(splitter.next()?);
+        Some((LineKind::Action, nick, splitter.next()?.trim()))
+    } else {
+        None
+    }
 }
 
-impl<'a> Entry<'a> {
-    /// Parse an entry from a line
-    pub fn from_line(line: &'a str) -> Option<Self> {
-        let mut splitter = line.splitn(4, |c: char| c.is_ascii_whitespace());
+/// WeeChat logs: `date time\tprefix\tmsg`, where the prefix is a nick
+/// or marks another kind of line (` *` for actions, `--` for notices
+/// and server messages, `-->` for joins, etc.)
+pub struct Weechat;
+
+impl LogFormat for Weechat {
+    fn name(&self) -> &str {
+        "weechat"
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> Option<Entry<'a>> {
+        let mut splitter = line.splitn(3, |c: char| c.is_ascii_whitespace());
         let date = splitter.next()?;
         let time = splitter.next()?;
-        let nick = normalize_nick(splitter.next()?);
-        let msg = splitter.next()?;
-        if nick == "-->" || nick == "<--" || nick == "--" {
-            None
-        } else {
-            Some(Entry {
-                date,
-                time,
-                nick,
-                msg,
-            })
-        }
+        let rest = splitter.next()?;
+        // the prefix can contain a space (` *`), the message starts after a tab
+        let (prefix, msg) = match rest.find('\t') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => {
+                let mut splitter = rest.splitn(2, |c: char| c.is_ascii_whitespace());
+                (splitter.next()?, splitter.next()?)
+            }
+        };
+        let (kind, nick, msg) = match prefix.trim() {
+            "" => return None,
+            "*" => {
+                // `alice waves`
+                let mut splitter = msg.splitn(2, ' ');
+                let nick = normalize_nick(splitter.next()?);
+                (LineKind::Action, nick, splitter.next()?.trim())
+            }
+            "--" if msg.starts_with("Notice(") => {
+                // `Notice(alice) -> #chan: msg` or `Notice(alice): msg`
+                let end = msg.find(')')?;
+                let nick = normalize_nick(&msg["Notice(".len()..end]);
+                let (_, msg) = msg[end..].split_once(": ")?;
+                (LineKind::Notice, nick, msg)
+            }
+            // joins, parts, nick changes, modes, errors
+            "-->" | "<--" | "--" | "=!=" => (LineKind::System, String::new(), msg),
+            nick => (LineKind::Message, normalize_nick(nick), msg),
+        };
+        Some(Entry {
+            date: date.into(),
+            time,
+            nick,
+            msg,
+            kind,
+        })
     }
 }
 
@@ -48,30 +357,261 @@ pub enum ParseRes<'a> {
 }
 
 impl<R: BufRead> Parser<R> {
-    pub fn new(r: R) -> Self {
+    /// Parse `r` with `format`, decoding lines that are not valid UTF-8
+    /// with `encoding`
+    pub fn new(r: R, format: Box<dyn LogFormat>, encoding: Encoding) -> Self {
         Self {
-            r,
+            r: decode::Decoder::new(r, encoding),
             buf: String::new(),
+            format,
+            entries: 0,
+            skipped: 0,
+            error: None,
+            bytes: Rc::new(Cell::new(0)),
         }
     }
 
+    /// Next entry. Read errors end the input, see `take_error`.
     pub fn next_entry(&mut self) -> ParseRes {
         self.buf.clear();
-        match self.r.read_line(&mut self.buf) {
-            Err(_) => ParseRes::Done,
+        match self.format.read_record(&mut self.r, &mut self.buf) {
+            Err(e) => {
+                self.error = Some(e);
+                ParseRes::Done
+            }
             Ok(0) => ParseRes::Done,
-            Ok(_) => match Entry::from_line(&self.buf.trim()) {
-                Some(e) => ParseRes::Yield(e),
-                None => ParseRes::Skip,
+            Ok(_) => match self.format.parse_line(self.buf.trim()) {
+                Some(e) => {
+                    self.entries += 1;
+                    ParseRes::Yield(e)
+                }
+                None => {
+                    self.skipped += 1;
+                    ParseRes::Skip
+                }
             },
         }
     }
+
+    /// Error that ended the input early, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Current line number
+    pub fn line(&self) -> usize {
+        self.r.line
+    }
+
+    /// Bytes read from the input file so far (compressed ones for
+    /// compressed files)
+    pub fn bytes(&self) -> u64 {
+        self.bytes.get()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            lines: self.r.line,
+            entries: self.entries,
+            skipped: self.skipped,
+            malformed: self.r.malformed,
+            malformed_lines: self.r.malformed_lines.clone(),
+            filtered: 0,
+            resumed: 0,
+        }
+    }
 }
 
-pub fn parse_file(f: &str) -> Fallible<Parser<Box<dyn BufRead>>> {
-    let r = Box::new({
-        let f = std::fs::File::open(f)?;
-        std::io::BufReader::new(f)
-    });
-    Ok(Parser::new(r))
+pub fn parse_file(
+    f: &path::Path,
+    mut format: Box<dyn LogFormat>,
+    encoding: Encoding,
+) -> Fallible<Parser<Box<dyn BufRead>>> {
+    format.start_file(f);
+    let (r, bytes) = input::open_counted(f)?;
+    let mut parser = Parser::new(r, format, encoding);
+    parser.bytes = bytes;
+    Ok(parser)
+}
+
+/// Files to parse for input `p`: `p` itself (which can be `-` for stdin),
+/// or all the files under it (sorted, hidden ones excepted) if it is a directory
+pub fn input_files(p: &path::Path) -> Fallible<Vec<path::PathBuf>> {
+    if !p.is_dir() {
+        return Ok(vec![p.to_path_buf()]);
+    }
+    let mut files = vec![];
+    let mut entries: Vec<_> = fs::read_dir(p)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for e in entries {
+        let hidden = e
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if !hidden {
+            files.extend(input_files(&e)?);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::test_dir};
+
+    fn weechat(line: &str) -> Option<(LineKind, String, String)> {
+        let e = Weechat.parse_line(line)?;
+        Some((e.kind, e.nick, e.msg.to_string()))
+    }
+
+    #[test]
+    fn weechat_line_kinds() {
+        let line = |kind, nick: &str, msg: &str| Some((kind, nick.to_string(), msg.to_string()));
+        assert_eq!(
+            weechat("2020-02-02 10:00:00\t+alice\thello there"),
+            line(LineKind::Message, "alice", "hello there")
+        );
+        assert_eq!(
+            weechat("2020-02-02 10:00:01\t *\t%bob waves at alice"),
+            line(LineKind::Action, "bob", "waves at alice")
+        );
+        assert_eq!(
+            weechat("2020-02-02 10:00:02\t--\tNotice(NickServ) -> alice: this nick is registered"),
+            line(LineKind::Notice, "nickserv", "this nick is registered")
+        );
+        assert_eq!(
+            weechat("2020-02-02 10:00:02\t--\tNotice(Carol): hi"),
+            line(LineKind::Notice, "carol", "hi")
+        );
+        assert_eq!(
+            weechat("2020-02-02 10:00:03\t--\talice is now known as alice_"),
+            line(LineKind::System, "", "alice is now known as alice_")
+        );
+        assert_eq!(
+            weechat("2020-02-02 10:00:04\t=!=\tirc: nick already in use"),
+            line(LineKind::System, "", "irc: nick already in use")
+        );
+        assert_eq!(
+            weechat("2020-02-02 10:00:05\t-->\tcarol (~c@host) has joined #chan"),
+            line(LineKind::System, "", "carol (~c@host) has joined #chan")
+        );
+        assert_eq!(weechat("2020-02-02 10:00:06\t\t"), None);
+        assert_eq!(weechat("garbage"), None);
+    }
+
+    #[test]
+    fn normalize_dates() {
+        for d in &["2020-02-29", "2020/2/29", "2020.02.29", "20200229"] {
+            assert_eq!(normalize_date(d).as_deref(), Some("2020-02-29"), "{}", d);
+        }
+        for d in &[
+            "29/02/2020",
+            "2020-13-01",
+            "2020-02-00",
+            "2020-02",
+            "2020-+2-29",
+            "",
+        ] {
+            assert_eq!(normalize_date(d), None, "{}", d);
+        }
+    }
+
+    #[test]
+    fn normalize_nick_strips_modes() {
+        for (raw, nick) in &[
+            ("~Dave", "dave"),
+            ("&erin", "erin"),
+            ("@op", "op"),
+            ("%half", "half"),
+            ("+voiced", "voiced"),
+            ("<@Alice>", "alice"),
+            (" bob ", "bob"),
+            // only prefixes are modes
+            ("c++", "c++"),
+            ("<+C++>", "c++"),
+            ("carol@", "carol@"),
+        ] {
+            assert_eq!(normalize_nick(raw), *nick);
+        }
+    }
+
+    #[test]
+    fn plausible_entries() {
+        use LineKind::*;
+        let entry = |date: &'static str, time, nick: &str, kind| Entry {
+            date: date.into(),
+            time,
+            nick: nick.into(),
+            msg: "hi",
+            kind,
+        };
+        assert!(plausible(&entry(
+            "2020-02-02",
+            "10:00:00",
+            "alice",
+            Message
+        )));
+        assert!(plausible(&entry("", "10:00", "[bob]", Action)));
+        assert!(plausible(&entry("", "", "", System)));
+        assert!(!plausible(&entry("10:00", "", "alice", Message)));
+        assert!(!plausible(&entry(
+            "2020-02-02",
+            "<@alice>",
+            "alice",
+            Message
+        )));
+        assert!(!plausible(&entry("", "", "<alice", Message)));
+        assert!(!plausible(&entry("", "", "alice bob", Message)));
+        assert!(!plausible(&entry("", "", "", Message)));
+    }
+
+    #[test]
+    fn detect_formats() {
+        let dir = test_dir("detect");
+        let detect = |name: &str, content: &str, custom: &HashMap<String, RegexFormatConfig>| {
+            let p = dir.join(name);
+            fs::write(&p, content).unwrap();
+            detect_format(&p, custom)
+                .map(|f| f.name().to_string())
+                .map_err(|e| e.to_string())
+        };
+        let builtin = HashMap::new();
+        let weechat = "2020-02-02 10:00:00\t@alice\thello there\n\
+                       2020-02-02 10:00:01\t-->\tbob (~b@host) has joined #chan\n\
+                       2020-02-02 10:00:02\tbob\thi alice\n";
+        assert_eq!(detect("w.log", weechat, &builtin).unwrap(), "weechat");
+        let irssi = "--- Log opened Sun Feb 02 10:00:00 2020\n\
+                     10:00 <@alice> hello there\n\
+                     10:01 * bob waves\n";
+        assert_eq!(detect("i.log", irssi, &builtin).unwrap(), "irssi");
+        let znc = "[10:00:00] <alice> hello there\n\
+                   [10:00:01] *** Joins: bob (b@host)\n\
+                   [10:00:02] <bob> hi alice\n";
+        assert_eq!(detect("2020-02-02.log", znc, &builtin).unwrap(), "znc");
+        let matrix =
+            r#"{"room_name": "r", "messages": [{"sender": "@alice:x", "origin_server_ts": 1}]}"#;
+        assert_eq!(detect("m.json", matrix, &builtin).unwrap(), "matrix");
+
+        // one line out of five parses, below the threshold
+        let notes = format!("{}notes\nabout nothing\nin particular\n\n", &weechat[..45]);
+        let e = detect("notes.txt", &notes, &builtin).unwrap_err();
+        assert!(e.contains("best guess is weechat with only 20%"), "{}", e);
+        assert!(detect("empty.log", "", &builtin).is_err());
+
+        // custom formats win ties
+        let mut custom = HashMap::new();
+        custom.insert(
+            "mine".to_string(),
+            RegexFormatConfig {
+                line: r"^(?P<date>\S+) (?P<time>\S+)\t(?P<nick>[^\t]+)\t(?P<msg>.*)$".into(),
+                skip: None,
+            },
+        );
+        let messages = "2020-02-02 10:00:00\talice\thello there\n";
+        assert_eq!(detect("w.log", messages, &custom).unwrap(), "mine");
+        assert_eq!(detect("w.log", weechat, &custom).unwrap(), "weechat");
+        assert_eq!(detect("i.log", irssi, &custom).unwrap(), "irssi");
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,162 @@
+/// Line-by-line decoding of logs that are not entirely valid UTF-8
+use std::io::{self, BufRead, Read};
+
+type Fallible<T> = crate::Fallible<T>;
+
+/// How to decode lines that are not valid UTF-8
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Replace invalid bytes with `U+FFFD`
+    Lossy,
+    Latin1,
+    Cp1252,
+}
+
+/// Maximum number of malformed line numbers kept for reporting
+const MAX_MALFORMED_LINES: usize = 10;
+
+impl Encoding {
+    pub fn from_name(s: &str) -> Fallible<Self> {
+        match s {
+            "lossy" => Ok(Encoding::Lossy),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "cp1252" | "windows-1252" => Ok(Encoding::Cp1252),
+            _ => Err(format!("unknown encoding {:?} (known: lossy, latin1, cp1252)", s).into()),
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+            Encoding::Cp1252 => bytes.iter().map(|&b| cp1252_char(b)).collect(),
+        }
+    }
+}
+
+fn cp1252_char(b: u8) -> char {
+    // the 0x80-0x9F range differs from latin-1, undefined bytes are kept as is
+    const TABLE: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž',
+        '\u{8F}', '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}',
+        'ž', 'Ÿ',
+    ];
+    match b {
+        0x80..=0x9F => TABLE[(b - 0x80) as usize],
+        _ => b as char,
+    }
+}
+
+/// Reader that turns each line of `r` into valid UTF-8,
+/// counting lines that needed the fallback encoding
+pub struct Decoder<R> {
+    r: R,
+    encoding: Encoding,
+    raw: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+    pub line: usize,                 // number of lines read so far
+    pub malformed: usize,            // lines that were not valid UTF-8
+    pub malformed_lines: Vec<usize>, // the first of these lines
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(r: R, encoding: Encoding) -> Self {
+        Decoder {
+            r,
+            encoding,
+            raw: vec![],
+            out: vec![],
+            pos: 0,
+            line: 0,
+            malformed: 0,
+            malformed_lines: vec![],
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Decoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.out.len() {
+            self.raw.clear();
+            self.out.clear();
+            self.pos = 0;
+            if self.r.read_until(b'\n', &mut self.raw)? > 0 {
+                self.line += 1;
+                if std::str::from_utf8(&self.raw).is_ok() {
+                    std::mem::swap(&mut self.raw, &mut self.out);
+                } else {
+                    self.malformed += 1;
+                    if self.malformed_lines.len() < MAX_MALFORMED_LINES {
+                        self.malformed_lines.push(self.line);
+                    }
+                    self.out = self.encoding.decode(&self.raw).into_bytes();
+                }
+            }
+        }
+        Ok(&self.out[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let avail = self.fill_buf()?;
+            let n = avail.len().min(buf.len());
+            buf[..n].copy_from_slice(&avail[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIXED: &[u8] = b"caf\xc3\xa9 ok\ncaf\xe9 \x80\nplain\n\x93hi\x94";
+
+    fn decode(input: &[u8], encoding: Encoding) -> (String, Decoder<&[u8]>) {
+        let mut d = Decoder::new(input, encoding);
+        let mut out = String::new();
+        d.read_to_string(&mut out).unwrap();
+        (out, d)
+    }
+
+    #[test]
+    fn mixed_lines() {
+        for &(encoding, expected) in &[
+            (
+                Encoding::Lossy,
+                "café ok\ncaf\u{fffd} \u{fffd}\nplain\n\u{fffd}hi\u{fffd}",
+            ),
+            (
+                Encoding::Latin1,
+                "café ok\ncafé \u{80}\nplain\n\u{93}hi\u{94}",
+            ),
+            (Encoding::Cp1252, "café ok\ncafé €\nplain\n“hi”"),
+        ] {
+            let (out, d) = decode(MIXED, encoding);
+            assert_eq!(out, expected, "{:?}", encoding);
+            assert_eq!(d.line, 4, "{:?}", encoding);
+            assert_eq!(d.malformed, 2, "{:?}", encoding);
+            assert_eq!(d.malformed_lines, [2, 4], "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn malformed_lines_kept() {
+        let input = b"\xff\n".repeat(MAX_MALFORMED_LINES + 2);
+        let (_, d) = decode(&input, Encoding::Latin1);
+        assert_eq!(d.malformed, MAX_MALFORMED_LINES + 2);
+        assert_eq!(
+            d.malformed_lines,
+            (1..=MAX_MALFORMED_LINES).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,173 @@
+/// Opening inputs: plain, gzip or zstd files, or stdin
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, BufRead, Read},
+    path,
+    rc::Rc,
+    sync::Mutex,
+};
+
+type Fallible<T> = crate::Fallible<T>;
+
+/// Name of the input that is read from stdin
+pub const STDIN: &str = "-";
+
+/// Bytes of stdin already read to detect its format, not consumed yet
+static STDIN_HEAD: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+pub fn is_stdin(f: &path::Path) -> bool {
+    f == path::Path::new(STDIN)
+}
+
+/// Wrap `r` into a decompressor, based on its first bytes
+fn decompress(mut r: Box<dyn BufRead>) -> Fallible<Box<dyn BufRead>> {
+    let magic = {
+        let buf = r.fill_buf()?;
+        buf[..buf.len().min(4)].to_vec()
+    };
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        // rotated logs are sometimes concatenated gzip members
+        let d = flate2::bufread::MultiGzDecoder::new(r);
+        Ok(Box::new(io::BufReader::new(d)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        let d = zstd::stream::read::Decoder::with_buffer(r)?;
+        Ok(Box::new(io::BufReader::new(d)))
+    } else {
+        Ok(r)
+    }
+}
+
+/// Number of bytes read from an input so far, shared with its reader
+pub type ByteCount = Rc<Cell<u64>>;
+
+/// Reader counting the bytes read through it
+struct Counted<R> {
+    r: R,
+    n: ByteCount,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.n.set(self.n.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Open input `f` (`-` for stdin), decompressing it if needed
+pub fn open(f: &path::Path) -> Fallible<Box<dyn BufRead>> {
+    Ok(open_counted(f)?.0)
+}
+
+/// Read from `r` into `head` until it holds `max` bytes or `r` ends
+fn fill_head<R: Read>(head: &mut Vec<u8>, r: R, max: usize) -> io::Result<()> {
+    if head.len() < max {
+        let missing = (max - head.len()) as u64;
+        r.take(missing).read_to_end(head)?;
+    }
+    Ok(())
+}
+
+/// `r`, after the bytes of `head` that were read from it earlier
+fn after_head<R: Read>(head: &mut Vec<u8>, r: R) -> impl Read {
+    io::Cursor::new(std::mem::take(head)).chain(r)
+}
+
+/// Like `open`, also returning the number of bytes read from `f` so far
+/// (before decompression)
+pub fn open_counted(f: &path::Path) -> Fallible<(Box<dyn BufRead>, ByteCount)> {
+    let n = Rc::new(Cell::new(0));
+    let r: Box<dyn BufRead> = if is_stdin(f) {
+        Box::new(io::BufReader::new(Counted {
+            r: after_head(&mut STDIN_HEAD.lock().unwrap(), io::stdin()),
+            n: n.clone(),
+        }))
+    } else {
+        Box::new(io::BufReader::new(Counted {
+            r: fs::File::open(f)?,
+            n: n.clone(),
+        }))
+    };
+    Ok((decompress(r)?, n))
+}
+
+/// Open the beginning of `f`, without consuming it if it is stdin
+/// (at most `max` bytes of stdin are buffered)
+pub fn open_sample(f: &path::Path, max: usize) -> Fallible<Box<dyn BufRead>> {
+    if !is_stdin(f) {
+        return open(f);
+    }
+    let mut head = STDIN_HEAD.lock().unwrap();
+    fill_head(&mut head, io::stdin().lock(), max)?;
+    decompress(Box::new(io::Cursor::new(head.clone())))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::test_dir, std::io::Write};
+
+    const TEXT: &str = "2020-02-02 10:00:00\talice\thello there\n";
+
+    fn gzip(s: &str) -> Vec<u8> {
+        let mut e = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        e.write_all(s.as_bytes()).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn decompressed(bytes: Vec<u8>) -> String {
+        let mut s = String::new();
+        decompress(Box::new(io::Cursor::new(bytes)))
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        s
+    }
+
+    #[test]
+    fn compressions() {
+        assert_eq!(decompressed(TEXT.into()), TEXT);
+        assert_eq!(decompressed(b"a".to_vec()), "a");
+        assert_eq!(decompressed(vec![]), "");
+        assert_eq!(decompressed(gzip(TEXT)), TEXT);
+        let mut members = gzip(TEXT);
+        members.extend(gzip("second member\n"));
+        assert_eq!(decompressed(members), format!("{}second member\n", TEXT));
+        let zstd = zstd::encode_all(TEXT.as_bytes(), 0).unwrap();
+        assert_eq!(decompressed(zstd), TEXT);
+    }
+
+    #[test]
+    fn head_is_read_again() {
+        let mut r = io::Cursor::new(TEXT.as_bytes());
+        let mut head = vec![];
+        fill_head(&mut head, &mut r, 10).unwrap();
+        assert_eq!(head, &TEXT.as_bytes()[..10]);
+        // a larger sample reads only what is missing
+        fill_head(&mut head, &mut r, 20).unwrap();
+        fill_head(&mut head, &mut r, 15).unwrap();
+        assert_eq!(head, &TEXT.as_bytes()[..20]);
+        let mut s = String::new();
+        after_head(&mut head, r).read_to_string(&mut s).unwrap();
+        assert_eq!(s, TEXT);
+        assert!(head.is_empty());
+    }
+
+    #[test]
+    fn counts_compressed_bytes() {
+        let dir = test_dir("input");
+        for (name, bytes) in &[
+            ("w.log", TEXT.as_bytes().to_vec()),
+            ("w.log.gz", gzip(TEXT)),
+        ] {
+            let p = dir.join(name);
+            fs::write(&p, bytes).unwrap();
+            let (mut r, n) = open_counted(&p).unwrap();
+            let mut s = String::new();
+            r.read_to_string(&mut s).unwrap();
+            assert_eq!(s, TEXT);
+            assert_eq!(n.get(), bytes.len() as u64, "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
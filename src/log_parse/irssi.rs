@@ -0,0 +1,118 @@
+/// irssi logs: `HH:MM <@nick> msg`, with the date in `---` headers
+use super::{message_or_action, Entry, LogFormat};
+
+pub struct Irssi {
+    date: String, // current date, as `YYYY-MM-DD`
+}
+
+impl Irssi {
+    pub fn new() -> Self {
+        Irssi {
+            date: String::new(),
+        }
+    }
+}
+
+fn month(s: &str) -> Option<u32> {
+    let months = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    months.iter().position(|m| *m == s).map(|i| i as u32 + 1)
+}
+
+/// Parse the date out of `Tue Mar 14 10:00:00 2017` or `Wed Mar 15 2017`
+fn parse_date(s: &str) -> Option<String> {
+    let words: Vec<_> = s.split_whitespace().collect();
+    let (m, d, y) = match words.as_slice() {
+        &[_, m, d, _, y] | &[_, m, d, y] => (m, d, y),
+        _ => return None,
+    };
+    let m = month(m)?;
+    let d: u32 = d.parse().ok()?;
+    let y: u32 = y.parse().ok()?;
+    Some(format!("{:04}-{:02}-{:02}", y, m, d))
+}
+
+impl LogFormat for Irssi {
+    fn name(&self) -> &str {
+        "irssi"
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> Option<Entry<'a>> {
+        if line.starts_with("---") {
+            let header = line.trim_start_matches('-').trim();
+            let rest = header
+                .strip_prefix("Log opened")
+                .or_else(|| header.strip_prefix("Day changed"))?;
+            if let Some(date) = parse_date(rest) {
+                self.date = date;
+            }
+            return None;
+        }
+
+        let mut splitter = line.splitn(2, ' ');
+        let time = splitter.next()?;
+        if !time.contains(':') {
+            return None;
+        }
+        // not `-!- nick has joined`, `-!- nick has quit`, etc.
+        let (kind, nick, msg) = message_or_action(splitter.next()?.trim_start())?;
+        Some(Entry {
+            date: self.date.clone().into(),
+            time,
+            nick,
+            msg,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::log_parse::LineKind};
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            parse_date(" Tue Mar 14 10:00:00 2017"),
+            Some("2017-03-14".to_string())
+        );
+        assert_eq!(
+            parse_date("Wed Mar 15 2017"),
+            Some("2017-03-15".to_string())
+        );
+        assert_eq!(parse_date("Wed Foo 15 2017"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn lines() {
+        let mut f = Irssi::new();
+        assert_eq!(
+            f.parse_line("--- Log opened Tue Mar 14 10:00:00 2017"),
+            None
+        );
+        let e = f.parse_line("10:00 <@Alice> hello there").unwrap();
+        assert_eq!(
+            (&*e.date, e.time, e.kind, &*e.nick, e.msg),
+            (
+                "2017-03-14",
+                "10:00",
+                LineKind::Message,
+                "alice",
+                "hello there"
+            )
+        );
+        assert_eq!(
+            f.parse_line("10:01 -!- bob [b@host] has joined #chan"),
+            None
+        );
+        assert_eq!(f.parse_line("--- Day changed Wed Mar 15 2017"), None);
+        let e = f.parse_line("00:01  * +bob waves").unwrap();
+        assert_eq!(
+            (&*e.date, e.time, e.kind, &*e.nick, e.msg),
+            ("2017-03-15", "00:01", LineKind::Action, "bob", "waves")
+        );
+        assert_eq!(f.parse_line("not a log line"), None);
+    }
+}
@@ -0,0 +1,484 @@
+/// JSON chat exports: Matrix room exports, DiscordChatExporter, Telegram Desktop
+use {
+    super::{normalize_nick, Entry, LineKind, LogFormat},
+    std::{
+        collections::{HashSet, VecDeque},
+        fmt,
+        io::{self, BufRead},
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Matrix,
+    Discord,
+    Telegram,
+}
+
+/// Reads the whole export on the first record, then yields one
+/// `date\ttime\tnick\tmsg` record per text message
+pub struct Json {
+    kind: Kind,
+    loaded: bool,
+    records: VecDeque<String>,
+}
+
+#[derive(Deserialize)]
+struct MatrixExport {
+    messages: Vec<MatrixEvent>,
+}
+
+#[derive(Deserialize)]
+struct MatrixEvent {
+    #[serde(rename = "type")]
+    ty: String,
+    sender: String,
+    #[serde(default)]
+    origin_server_ts: u64,
+    #[serde(default)]
+    content: MatrixContent,
+}
+
+#[derive(Default, Deserialize)]
+struct MatrixContent {
+    #[serde(default)]
+    msgtype: String,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Deserialize)]
+struct DiscordExport {
+    messages: Vec<DiscordMessage>,
+}
+
+#[derive(Deserialize)]
+struct DiscordMessage {
+    #[serde(rename = "type", default)]
+    ty: String,
+    #[serde(default)]
+    timestamp: String,
+    #[serde(default)]
+    content: String,
+    author: DiscordAuthor,
+}
+
+#[derive(Deserialize)]
+struct DiscordAuthor {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    nickname: Option<String>,
+}
+
+/// Either a single chat, or a full account export with `chats.list`
+#[derive(Deserialize)]
+struct TelegramExport {
+    #[serde(default)]
+    messages: Vec<TelegramMessage>,
+    chats: Option<TelegramChats>,
+}
+
+#[derive(Deserialize)]
+struct TelegramChats {
+    list: Vec<TelegramExport>,
+}
+
+#[derive(Deserialize)]
+struct TelegramMessage {
+    #[serde(rename = "type", default)]
+    ty: String,
+    #[serde(default)]
+    date: String,
+    from: Option<String>,
+    from_id: Option<serde_json::Value>,
+    #[serde(default)]
+    text: TelegramText,
+}
+
+/// Text is either a string, or a list of strings and formatted pieces
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TelegramText {
+    Plain(String),
+    Pieces(Vec<TelegramPiece>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TelegramPiece {
+    Plain(String),
+    Formatted { text: String },
+}
+
+impl Default for TelegramText {
+    fn default() -> Self {
+        TelegramText::Plain(String::new())
+    }
+}
+
+impl fmt::Display for TelegramText {
+    /// The text without its formatting
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TelegramText::Plain(s) => f.write_str(s),
+            TelegramText::Pieces(v) => {
+                for p in v.iter() {
+                    match p {
+                        TelegramPiece::Plain(s) => f.write_str(s)?,
+                        TelegramPiece::Formatted { text } => f.write_str(text)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// UTF-8 byte order mark
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Keys of the objects in `head`, the beginning of a JSON document, at
+/// any depth. `None` if it does not start like a JSON object.
+fn sniff_keys(head: &[u8]) -> Option<HashSet<String>> {
+    let head = head.strip_prefix(BOM).unwrap_or(head);
+    let start = head.iter().position(|c| !c.is_ascii_whitespace())?;
+    if head[start] != b'{' {
+        return None;
+    }
+    let mut keys = HashSet::new();
+    let mut string: Option<Vec<u8>> = None; // inside a string
+    let mut escaped = false;
+    let mut last: Option<Vec<u8>> = None; // string just closed, a key if `:` follows
+    for &c in &head[start..] {
+        if let Some(mut s) = string.take() {
+            if !escaped && c == b'"' {
+                last = Some(s);
+            } else {
+                escaped = !escaped && c == b'\\';
+                s.push(c);
+                string = Some(s);
+            }
+            continue;
+        }
+        match c {
+            b'"' => string = Some(vec![]),
+            b':' => {
+                if let Some(k) = last.take() {
+                    keys.insert(String::from_utf8_lossy(&k).into_owned());
+                }
+            }
+            _ if c.is_ascii_whitespace() => continue,
+            _ => (),
+        }
+        last = None;
+    }
+    Some(keys)
+}
+
+/// Turn a display name or ID into a nick
+fn nick_of_name(s: &str) -> String {
+    normalize_nick(&s.split_whitespace().collect::<Vec<_>>().join("_"))
+}
+
+/// Split `2020-01-31T10:00:00.123+00:00` into date and time
+fn split_iso8601(s: &str) -> (String, String) {
+    let mut splitter = s.splitn(2, 'T');
+    let date = splitter.next().unwrap_or("").to_string();
+    let time = splitter.next().unwrap_or("");
+    let time = time.get(..8).unwrap_or(time).to_string();
+    (date, time)
+}
+
+/// Date and time (UTC) of a timestamp in milliseconds since the epoch
+fn split_timestamp_ms(ts: u64) -> (String, String) {
+    let secs = ts / 1000;
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (
+        format!("{:04}-{:02}-{:02}", y, m, d),
+        format!("{:02}:{:02}:{:02}", rem / 3600, rem / 60 % 60, rem % 60),
+    )
+}
+
+fn telegram_messages(export: TelegramExport, out: &mut Vec<(String, String, String, String)>) {
+    for m in export.messages {
+        if m.ty != "message" {
+            continue; // service messages
+        }
+        let nick = match (m.from, m.from_id) {
+            (Some(ref name), _) if name.trim() != "" => nick_of_name(name),
+            (_, Some(serde_json::Value::String(id))) => nick_of_name(&id),
+            (_, Some(id)) => nick_of_name(&id.to_string()),
+            _ => continue,
+        };
+        let (date, time) = split_iso8601(&m.date);
+        out.push((date, time, nick, m.text.to_string()));
+    }
+    if let Some(chats) = export.chats {
+        for chat in chats.list {
+            telegram_messages(chat, out)
+        }
+    }
+}
+
+impl Json {
+    pub fn new(kind: Kind) -> Self {
+        Json {
+            kind,
+            loaded: false,
+            records: VecDeque::new(),
+        }
+    }
+
+    /// Parse the whole export into `(date, time, nick, msg)`
+    fn messages(
+        &self,
+        r: &mut dyn BufRead,
+    ) -> serde_json::Result<Vec<(String, String, String, String)>> {
+        let mut out = vec![];
+        // exports saved by Windows tools may start with a byte order mark,
+        // which serde_json rejects
+        if r.fill_buf()
+            .map_err(serde_json::Error::io)?
+            .starts_with(BOM)
+        {
+            r.consume(BOM.len());
+        }
+        match self.kind {
+            Kind::Matrix => {
+                let export: MatrixExport = serde_json::from_reader(r)?;
+                for ev in export.messages {
+                    if ev.ty != "m.room.message" || ev.content.msgtype != "m.text" {
+                        continue;
+                    }
+                    // `@alice:example.org` -> `alice`
+                    let localpart = ev.sender.trim_start_matches('@').split(':').next();
+                    let nick = nick_of_name(localpart.unwrap_or(""));
+                    let (date, time) = split_timestamp_ms(ev.origin_server_ts);
+                    out.push((date, time, nick, ev.content.body));
+                }
+            }
+            Kind::Discord => {
+                let export: DiscordExport = serde_json::from_reader(r)?;
+                for m in export.messages {
+                    if m.ty != "Default" && m.ty != "Reply" {
+                        continue;
+                    }
+                    let a = m.author;
+                    let name = match a.nickname {
+                        Some(ref n) if n.trim() != "" => n,
+                        _ if a.name.trim() != "" => &a.name,
+                        _ => &a.id,
+                    };
+                    let (date, time) = split_iso8601(&m.timestamp);
+                    out.push((date, time, nick_of_name(name), m.content));
+                }
+            }
+            Kind::Telegram => {
+                let export: TelegramExport = serde_json::from_reader(r)?;
+                telegram_messages(export, &mut out);
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl LogFormat for Json {
+    fn name(&self) -> &str {
+        match self.kind {
+            Kind::Matrix => "matrix",
+            Kind::Discord => "discord",
+            Kind::Telegram => "telegram",
+        }
+    }
+
+    /// Exports are single JSON documents, too big to be parsed for
+    /// detection: look for keys that only appear in one kind of export
+    fn sniff(&self, head: &[u8]) -> Option<f64> {
+        let keys = match sniff_keys(head) {
+            Some(keys) => keys,
+            None => return Some(0.),
+        };
+        let has = |k: &str| keys.contains(k);
+        let found = match self.kind {
+            Kind::Matrix => has("origin_server_ts") || has("event_id") || has("room_name"),
+            Kind::Discord => {
+                has("guild") || has("messageCount") || has("author") && has("timestamp")
+            }
+            Kind::Telegram => has("from_id") || has("date_unixtime") || has("personal_information"),
+        };
+        Some(if found { 1. } else { 0. })
+    }
+
+    fn read_record(&mut self, r: &mut dyn BufRead, buf: &mut String) -> io::Result<usize> {
+        if !self.loaded {
+            self.loaded = true;
+            let messages = self
+                .messages(r)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            for (date, time, nick, msg) in messages {
+                // records are single lines, without tabs in the nick
+                let msg = msg.split_whitespace().collect::<Vec<_>>().join(" ");
+                if nick.is_empty() || msg.is_empty() {
+                    continue;
+                }
+                self.records.push_back(format!(
+                    "{}\t{}\t{}\t{}",
+                    date,
+                    time,
+                    nick.replace('\t', "_"),
+                    msg
+                ));
+            }
+        }
+        match self.records.pop_front() {
+            Some(rec) => {
+                buf.push_str(&rec);
+                Ok(rec.len())
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> Option<Entry<'a>> {
+        let mut splitter = line.splitn(4, '\t');
+        let date = splitter.next()?;
+        let time = splitter.next()?;
+        let nick = splitter.next()?.to_string();
+        let msg = splitter.next()?;
+        Some(Entry {
+            date: date.into(),
+            time,
+            nick,
+            msg,
+            kind: LineKind::Message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records read from `export`
+    fn records(kind: Kind, export: &str) -> Vec<String> {
+        let mut f = Json::new(kind);
+        let mut r = io::Cursor::new(export.as_bytes());
+        let mut out = vec![];
+        loop {
+            let mut buf = String::new();
+            if f.read_record(&mut r, &mut buf).unwrap() == 0 {
+                return out;
+            }
+            out.push(buf);
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        let split = |date: &str, time: &str| (date.to_string(), time.to_string());
+        assert_eq!(split_timestamp_ms(0), split("1970-01-01", "00:00:00"));
+        assert_eq!(
+            split_timestamp_ms(1_600_000_000_999),
+            split("2020-09-13", "12:26:40")
+        );
+        assert_eq!(
+            split_timestamp_ms(951_782_400_000),
+            split("2000-02-29", "00:00:00")
+        );
+        assert_eq!(
+            split_iso8601("2020-01-31T10:00:00.123+00:00"),
+            split("2020-01-31", "10:00:00")
+        );
+        assert_eq!(
+            split_iso8601("2020-01-31T10:00"),
+            split("2020-01-31", "10:00")
+        );
+    }
+
+    #[test]
+    fn exports() {
+        let matrix = r#"{"room_name":"r","messages":[
+            {"type":"m.room.message","sender":"@Alice:matrix.org","origin_server_ts":1600000000000,"content":{"msgtype":"m.text","body":"hello\nworld"}},
+            {"type":"m.room.message","sender":"@bob:x","origin_server_ts":1600000000000,"content":{"msgtype":"m.image","body":"cat.png"}},
+            {"type":"m.room.member","sender":"@bob:x","content":{}}]}"#;
+        assert_eq!(
+            records(Kind::Matrix, matrix),
+            vec!["2020-09-13\t12:26:40\talice\thello world"]
+        );
+        let discord = r#"{"guild":{},"messages":[
+            {"id":"1","type":"Default","timestamp":"2020-01-01T10:00:00.123+00:00","content":"hey there","author":{"id":"9","name":"bob","nickname":"Bobby B"}},
+            {"id":"2","type":"ChannelPinnedMessage","timestamp":"2020-01-01T10:00:01+00:00","content":"","author":{"id":"9","name":"bob"}},
+            {"id":"3","type":"Reply","timestamp":"2020-01-01T10:00:02+00:00","content":"yes","author":{"id":"8","name":"Carol"}}]}"#;
+        assert_eq!(
+            records(Kind::Discord, discord),
+            vec![
+                "2020-01-01\t10:00:00\tbobby_b\they there",
+                "2020-01-01\t10:00:02\tcarol\tyes",
+            ]
+        );
+        let telegram = r#"{"chats":{"list":[{"name":"x","messages":[
+            {"id":1,"type":"message","date":"2020-01-01T10:00:00","from":"Carol","from_id":"user1","text":["hi ",{"type":"bold","text":"there"}]},
+            {"id":2,"type":"service","date":"2020-01-01T10:00:00","actor":"x"},
+            {"id":3,"type":"message","date":"2020-01-01T10:00:05","from":null,"from_id":"user2","text":"anyone?"}]}]}}"#;
+        let recs = records(Kind::Telegram, telegram);
+        assert_eq!(
+            recs,
+            vec![
+                "2020-01-01\t10:00:00\tcarol\thi there",
+                "2020-01-01\t10:00:05\tuser2\tanyone?",
+            ]
+        );
+        // a byte order mark before the export is skipped
+        assert_eq!(
+            records(Kind::Telegram, &format!("\u{feff}{}", telegram)),
+            recs
+        );
+        let e = Json::new(Kind::Telegram).parse_line(&recs[0]).unwrap();
+        assert_eq!(
+            (&*e.date, e.time, &*e.nick, e.msg, e.kind),
+            (
+                "2020-01-01",
+                "10:00:00",
+                "carol",
+                "hi there",
+                LineKind::Message
+            )
+        );
+    }
+
+    #[test]
+    fn sniff() {
+        let discord = br#"{"guild":{"id":"1"},"messages":[{"id":"1","type":"Default","timestamp":"2020-01-01T10:00:00.123+00:00","content":"a \"quoted\": b","author":{"id":"9","name":"bob"}}"#;
+        let matrix = br#"  {"room_name": "r", "messages": [{"type": "m.room.message", "sender": "@alice:x", "origin_server_ts": 1600000000000"#;
+        let telegram = b"\xef\xbb\xbf{\n \"name\": \"x\",\n \"messages\": [{\"id\": 1, \"from\": \"Carol\", \"from_id\": \"user1\"";
+        let keys = sniff_keys(discord).unwrap();
+        assert!(keys.contains("timestamp") && keys.contains("author"));
+        // strings that are values are not keys, even when they end with `:`
+        assert!(!keys.contains("Default") && !keys.contains("a \\\"quoted\\\": b"));
+        assert_eq!(sniff_keys(b"2020-01-01 10:00 <a> {\"x\": 1}"), None);
+        for (kind, head) in &[
+            (Kind::Discord, &discord[..]),
+            (Kind::Matrix, &matrix[..]),
+            (Kind::Telegram, &telegram[..]),
+        ] {
+            for other in &[Kind::Matrix, Kind::Discord, Kind::Telegram] {
+                let expected = if other == kind { 1. } else { 0. };
+                assert_eq!(Json::new(*other).sniff(head), Some(expected), "{:?}", other);
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+/// Log format defined in the config by a regex
+use {
+    super::{normalize_date, normalize_nick, Entry, LineKind, LogFormat},
+    crate::config::RegexFormatConfig,
+    regex::Regex,
+};
+
+type Fallible<T> = crate::Fallible<T>;
+
+pub struct RegexFormat {
+    name: String,
+    line: Regex,
+    skip: Option<Regex>,
+}
+
+impl RegexFormat {
+    pub fn new(name: &str, c: &RegexFormatConfig) -> Fallible<Self> {
+        Ok(RegexFormat {
+            name: name.to_string(),
+            line: Regex::new(&c.line)?,
+            skip: match c.skip {
+                Some(ref s) => Some(Regex::new(s)?),
+                None => None,
+            },
+        })
+    }
+}
+
+impl LogFormat for RegexFormat {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> Option<Entry<'a>> {
+        if let Some(ref skip) = self.skip {
+            if skip.is_match(line) {
+                return None;
+            }
+        }
+        let caps = self.line.captures(line)?;
+        let field = |n: &str| caps.name(n).map_or("", |m| m.as_str());
+        let nick = normalize_nick(field("nick"));
+        let msg = field("msg").trim();
+        if nick.is_empty() || msg.is_empty() {
+            return None;
+        }
+        // `--since`, `--until` and `--bucket` compare `YYYY-MM-DD` dates,
+        // a line with a date that cannot be read is not parsed
+        let date = match caps.name("date") {
+            Some(d) => normalize_date(d.as_str())?,
+            None => String::new(),
+        };
+        Some(Entry {
+            date: date.into(),
+            time: field("time"),
+            nick,
+            msg,
+            kind: LineKind::Message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(line: &str, skip: Option<&str>) -> RegexFormat {
+        let c = RegexFormatConfig {
+            line: line.to_string(),
+            skip: skip.map(|s| s.to_string()),
+        };
+        RegexFormat::new("mybot", &c).unwrap()
+    }
+
+    #[test]
+    fn lines() {
+        let mut f = format(
+            r"^(?:(?P<date>[\d/.-]+) )?(?:(?P<time>\d\d:\d\d) )?<(?P<nick>[^>]+)> (?P<msg>.*)$",
+            Some(r"^\S+ \S+ \*\*\* "),
+        );
+        let e = f.parse_line("2020/2/29 10:00 <Alice> hello there").unwrap();
+        assert_eq!(
+            (&*e.date, e.time, &*e.nick, e.msg, e.kind),
+            (
+                "2020-02-29",
+                "10:00",
+                "alice",
+                "hello there",
+                LineKind::Message
+            )
+        );
+        // `date` and `time` are optional
+        let e = f.parse_line("10:00 <bob> hi").unwrap();
+        assert_eq!((&*e.date, e.time, &*e.nick), ("", "10:00", "bob"));
+        let e = f.parse_line("<bob> hi").unwrap();
+        assert_eq!((&*e.date, e.time), ("", ""));
+        assert_eq!(f.parse_line("29/02/2020 <bob> hi"), None);
+        assert_eq!(f.parse_line("2020-02-29 10:00 *** bob joined"), None);
+        assert_eq!(f.parse_line("2020-02-29 10:00 <bob> "), None);
+        assert_eq!(f.parse_line("garbage"), None);
+    }
+}
@@ -0,0 +1,98 @@
+/// ZNC log module: `[HH:MM:SS] <nick> msg`, one file per day named `YYYY-MM-DD.log`
+use {
+    super::{message_or_action, normalize_date, Entry, LogFormat},
+    std::path,
+};
+
+pub struct Znc {
+    date: String, // from the file name, as `YYYY-MM-DD`
+}
+
+impl Znc {
+    pub fn new() -> Self {
+        Znc {
+            date: String::new(),
+        }
+    }
+}
+
+/// Date from a file stem like `2017-03-14` or `network_#chan_20170314`
+fn date_of_stem(stem: &str) -> Option<String> {
+    [10, 8]
+        .iter()
+        .filter_map(|&n| stem.len().checked_sub(n).and_then(|i| stem.get(i..)))
+        .find_map(normalize_date)
+}
+
+impl LogFormat for Znc {
+    fn name(&self) -> &str {
+        "znc"
+    }
+
+    fn start_file(&mut self, p: &path::Path) {
+        self.date = p
+            .file_stem()
+            .and_then(|s| date_of_stem(&s.to_string_lossy()))
+            .unwrap_or_default();
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> Option<Entry<'a>> {
+        if !line.starts_with('[') {
+            return None;
+        }
+        let end = line.find(']')?;
+        let time = &line[1..end];
+        // not `*** Joins: nick (user@host)`, `*** nick sets mode: +o foo`, etc.
+        let (kind, nick, msg) = message_or_action(line[end + 1..].trim_start())?;
+        Some(Entry {
+            date: self.date.clone().into(),
+            time,
+            nick,
+            msg,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::log_parse::LineKind};
+
+    #[test]
+    fn dates_of_stems() {
+        let date = Some("2017-03-14".to_string());
+        assert_eq!(date_of_stem("2017-03-14"), date);
+        assert_eq!(date_of_stem("freenode_#chan_20170314"), date);
+        assert_eq!(date_of_stem("freenode_#chan_2017-03-14"), date);
+        assert_eq!(date_of_stem("2017-3-14"), None);
+        // digits that are not a date
+        assert_eq!(date_of_stem("chan_12345678"), None);
+        assert_eq!(date_of_stem("chan_2017-13-14"), None);
+        assert_eq!(date_of_stem("chan"), None);
+        assert_eq!(date_of_stem(""), None);
+    }
+
+    #[test]
+    fn lines() {
+        let mut f = Znc::new();
+        f.start_file(path::Path::new("logs/#chan/2017-03-14.log"));
+        let e = f.parse_line("[10:00:00] <Alice> hello there").unwrap();
+        assert_eq!(
+            (&*e.date, e.time, e.kind, &*e.nick, e.msg),
+            (
+                "2017-03-14",
+                "10:00:00",
+                LineKind::Message,
+                "alice",
+                "hello there"
+            )
+        );
+        let e = f.parse_line("[10:00:01] * bob waves").unwrap();
+        assert_eq!(
+            (e.kind, &*e.nick, e.msg),
+            (LineKind::Action, "bob", "waves")
+        );
+        assert_eq!(f.parse_line("[10:00:02] *** Joins: carol (c@host)"), None);
+        assert_eq!(f.parse_line("hello"), None);
+    }
+}
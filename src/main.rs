@@ -1,13 +1,17 @@
 use {
     irc::client::prelude::*,
-    markov::Chain as MChain,
+    signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        iterator::Signals,
+    },
     std::{
         collections::HashMap,
         error::Error,
         ffi::OsStr,
         fs::{self, File},
+        io::{BufRead, BufReader, Write},
         path,
-        sync::{Arc, Mutex},
+        sync::{atomic::AtomicBool, Arc, Mutex},
         thread, time,
     },
 };
@@ -15,65 +19,137 @@ use {
 #[macro_use]
 extern crate serde_derive;
 
+/// Whether `info!` prints on stderr, to keep stdout for `generate --json`
+static INFO_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Print a diagnostic, like `println!`
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if crate::INFO_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod aliases;
+mod backoff;
+mod casemapping;
+mod config;
+mod generate;
 mod log_parse;
+mod nick_file;
+mod progress;
+mod raw_chain;
+mod reply;
+mod sources;
+mod spill;
 
 /// Temporary storage of chains
 pub struct Chains {
     data_dir: path::PathBuf,
     ttl: time::Duration, // number of seconds before discarding
     cached: HashMap<String, CachedChain>,
+    aliases: aliases::Aliases,             // to resolve nicks
+    order: usize,                          // of the chains created by `learn`
+    config_orders: HashMap<String, usize>, // per nick, as written in the config
+    orders: HashMap<String, usize>,        // the same by canonical nick, overriding `order`
+    backoff: Option<Vec<f64>>,             // weights of the orders, if mixed
 }
 
 /// Chain cached in memory (with "last used" timestamp for eviction)
 pub struct CachedChain {
     last_used: time::Instant,
-    chain: Arc<Chain>,
-}
-
-/// Chain for a nick
-#[derive(Serialize, Deserialize)]
-pub struct Chain {
-    nick: String,
-    c: MChain<String>,
+    dirty: bool, // modified since last saved
+    chain: Arc<backoff::Backoff>,
 }
 
 /// A generic type of errors
 pub type Fallible<T> = Result<T, Box<Error>>;
 
-impl Chain {
-    pub fn new(n: &str) -> Self {
-        Chain {
-            nick: n.to_owned(),
-            c: MChain::new(),
-        }
-    }
-}
-
 impl CachedChain {
     pub fn touch(&mut self) {
         self.last_used = time::Instant::now();
     }
-    pub fn from_path(nick: &str, p: &path::Path) -> Fallible<Self> {
-        let r = std::io::BufReader::new(File::open(p)?);
-        let c: MChain<String> = bincode::deserialize_from(r)?;
-        Ok(CachedChain {
+    fn new(chain: backoff::Backoff) -> Self {
+        CachedChain {
             last_used: time::Instant::now(),
-            chain: Arc::new(Chain {
-                nick: nick.into(),
-                c,
-            }),
-        })
+            dirty: false,
+            chain: Arc::new(chain),
+        }
     }
 }
 
-const DATA_DIR: &'static str = "./data";
+/// Start of chain files, followed by the order of the chain, then the chain.
+/// Older files start directly with the chain, whose first 8 bytes (its
+/// number of states) can never be these.
+const CHAIN_MAGIC: &[u8; 8] = b"charlie\x01";
 
-fn path_for_nick(data_dir: &path::Path, nick: &str) -> path::PathBuf {
+/// Write `c`, a chain of order `order`, to `p`, going through a temporary
+/// file so that readers never see a half-written chain
+fn save_chain<C: serde::Serialize>(p: &path::Path, order: usize, c: &C) -> Fallible<()> {
+    let tmp = p.with_extension("bin.tmp");
+    {
+        let mut w = std::io::BufWriter::new(File::create(&tmp)?);
+        w.write_all(CHAIN_MAGIC)?;
+        bincode::serialize_into(&mut w, &(order as u64))?;
+        bincode::serialize_into(&mut w, c)?;
+    }
+    fs::rename(&tmp, p)?;
+    Ok(())
+}
+
+/// Order stored in the header of the chain file read by `r`, if it has one
+fn read_header<R: BufRead>(r: &mut R) -> Fallible<Option<usize>> {
+    if !r.fill_buf()?.starts_with(CHAIN_MAGIC) {
+        return Ok(None);
+    }
+    r.consume(CHAIN_MAGIC.len());
+    let order: u64 = bincode::deserialize_from(r)?;
+    Ok(Some(order as usize))
+}
+
+/// Read the chain stored at `p`, and its order if it was stored with a header
+fn load_chain<C: serde::de::DeserializeOwned>(p: &path::Path) -> Fallible<(Option<usize>, C)> {
+    let mut r = BufReader::new(File::open(p)?);
+    let order = read_header(&mut r)?;
+    Ok((order, bincode::deserialize_from(r)?))
+}
+
+/// Order of the chain stored at `p`, reading only its header if it has one
+fn chain_order(p: &path::Path) -> Fallible<usize> {
+    match read_header(&mut BufReader::new(File::open(p)?))? {
+        Some(order) => Ok(order),
+        None => Ok(raw_chain::RawChain::load(p)?.order),
+    }
+}
+
+pub const DATA_DIR: &str = "./data";
+
+/// Empty directory for the test `name`
+#[cfg(test)]
+fn test_dir(name: &str) -> path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("charliebot-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Path of the reverse chain stored next to the chain at `p`
+/// (`data/alice.rev.bin` for `data/alice.bin`)
+fn reverse_path(p: &path::Path) -> path::PathBuf {
+    p.with_extension("rev.bin")
+}
+
+/// Path of the chain for `nick` (or `nick@period` for a time-sliced chain),
+/// fails if `nick` cannot be stored safely
+fn path_for_nick(data_dir: &path::Path, nick: &str) -> Fallible<path::PathBuf> {
     let mut path = path::PathBuf::new();
     path.push(data_dir);
-    path.push(nick);
+    path.push(nick_file::encode_chain(nick)?);
     path.set_extension("bin");
-    path
+    Ok(path)
 }
 
 impl Chains {
@@ -82,6 +158,11 @@ impl Chains {
             data_dir: p.into(),
             ttl: std::time::Duration::from_secs(20),
             cached: HashMap::new(),
+            aliases: aliases::Aliases::default(),
+            order: 1,
+            config_orders: HashMap::new(),
+            orders: HashMap::new(),
+            backoff: None,
         }
     }
     pub fn new() -> Self {
@@ -96,18 +177,44 @@ impl Chains {
                 Err(..) => continue,
             };
             if path.extension() == Some(OsStr::new("bin")) {
-                v.push(path.file_stem().unwrap().to_string_lossy().to_string())
+                let stem = path.file_stem().unwrap().to_string_lossy();
+                if let Some(nick) = nick_file::decode(&stem) {
+                    v.push(nick)
+                }
             }
         }
         Ok(v)
     }
 
-    // cleanup old entries
+    /// Periods for which `nick` has a time-sliced chain, sorted
+    pub fn periods(&self, nick: &str) -> Fallible<Vec<String>> {
+        let mut v = vec![];
+        for p in fs::read_dir(&self.data_dir)? {
+            let path = match p {
+                Ok(x) => x.path(),
+                Err(..) => continue,
+            };
+            if path.extension() == Some(OsStr::new("bin")) {
+                let stem = path.file_stem().unwrap().to_string_lossy();
+                if let Some(name) = nick_file::decode_chain(&stem) {
+                    if let (n, Some(period)) = nick_file::split_period(&name) {
+                        if n == nick {
+                            v.push(period.to_string())
+                        }
+                    }
+                }
+            }
+        }
+        v.sort();
+        Ok(v)
+    }
+
+    // cleanup old entries (dirty ones are kept until they are saved)
     fn cleanup(&mut self) {
         let now = time::Instant::now();
         let ttl = self.ttl;
         self.cached.retain(|nick, c| {
-            let keep = now - c.last_used <= ttl;
+            let keep = c.dirty || now - c.last_used <= ttl;
             if !keep {
                 println!("cleanup entry for `{}`", nick);
             }
@@ -115,80 +222,201 @@ impl Chains {
         });
     }
 
-    /// Find chain for this nickname
-    pub fn find_nick(&mut self, nick: &str) -> Option<Arc<Chain>> {
+    /// Lowercase nicks with `cm` from now on, renaming the chains
+    /// stored with another mapping
+    pub fn set_casemapping(&mut self, cm: casemapping::Casemapping) -> Fallible<()> {
+        if cm == self.aliases.casemapping() {
+            return Ok(());
+        }
+        // cached chains are named after the old mapping
+        self.save_dirty()?;
+        self.cached.clear();
+        self.aliases.set_casemapping(cm);
+        self.aliases.rename_chains(&self.data_dir)?;
+        self.set_orders(self.config_orders.clone());
+        Ok(())
+    }
+
+    /// Use `orders` (per nick, as written in the config) for new chains
+    fn set_orders(&mut self, orders: HashMap<String, usize>) {
+        self.orders = self.aliases.canonical_keys(&orders);
+        self.config_orders = orders;
+    }
+
+    /// `chain` with the orders to generate from: mixed if back-off is
+    /// enabled, alone otherwise
+    fn with_orders(&self, chain: raw_chain::RawChainPair) -> backoff::Backoff {
+        match self.backoff {
+            Some(ref weights) => backoff::Backoff::new(chain, weights),
+            None => backoff::Backoff::single(chain),
+        }
+    }
+
+    /// Load the chain stored at `p` into the cache
+    fn load(&mut self, nick: &str, p: &path::Path) -> Fallible<()> {
+        let chain = self.with_orders(raw_chain::RawChainPair::load(p)?);
+        self.cached
+            .insert(nick.to_string(), CachedChain::new(chain));
+        Ok(())
+    }
+
+    /// What to reply from for `nick`
+    pub fn find_source(&mut self, nick: &str) -> Option<Arc<backoff::Backoff>> {
+        let nick = &self.aliases.canonical(nick);
         let mut opt = self.cached.get_mut(nick);
         if let Some(ref mut c) = opt {
             c.touch();
             opt.map(|c| c.chain.clone())
         } else {
-            let path = path_for_nick(&self.data_dir, nick);
-            let c = CachedChain::from_path(nick, &path).ok();
-            if let Some(c) = c {
-                self.cached.insert(nick.to_string(), c);
+            let path = match path_for_nick(&self.data_dir, nick) {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("invalid nick {:?}: {}", nick, e);
+                    return None;
+                }
+            };
+            if self.load(nick, &path).is_ok() {
                 self.cached.get(nick).map(|c| c.chain.clone())
             } else {
                 println!(
                     "could not load chain for nick {:?} (path: {:?})",
                     nick, path
                 );
+                if let Ok(periods) = self.periods(nick_file::split_period(nick).0) {
+                    if !periods.is_empty() {
+                        println!("known periods for {:?}: {:?}", nick, periods);
+                    }
+                }
                 None
             }
         }
     }
-}
 
-fn read_file(s: &str) -> Fallible<HashMap<String, Chain>> {
-    let mut parser = log_parse::parse_file(s)?;
-    let mut chains = HashMap::new();
-    loop {
-        match parser.next_entry() {
-            log_parse::ParseRes::Skip => (),
-            log_parse::ParseRes::Done => break,
-            log_parse::ParseRes::Yield(record) => {
-                //println!("parsed record {:?}", &record);
-                let c = {
-                    if !chains.contains_key(&record.nick) {
-                        chains.insert(record.nick.to_string(), Chain::new(&record.nick));
-                    }
-                    chains.get_mut(&record.nick).unwrap()
-                };
-                c.c.feed_str(record.msg);
+    /// Order of a new chain for `nick`; existing chains keep theirs
+    fn order_for(&self, nick: &str) -> usize {
+        self.orders.get(nick).cloned().unwrap_or(self.order)
+    }
+
+    /// Feed `msg` into the chain for `nick`, creating it if needed
+    pub fn learn(&mut self, nick: &str, msg: &str) {
+        let nick = &self.aliases.canonical(nick);
+        if !self.cached.contains_key(nick) {
+            let path = match path_for_nick(&self.data_dir, nick) {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("cannot learn for {:?}: {}", nick, e);
+                    return;
+                }
+            };
+            if path.exists() {
+                if let Err(e) = self.load(nick, &path) {
+                    // do not overwrite a file we failed to read
+                    println!(
+                        "cannot learn for {:?}: error loading {:?}: {}",
+                        nick, path, e
+                    );
+                    return;
+                }
+            } else {
+                let order = self.order_for(nick);
+                let chain = self.with_orders(raw_chain::RawChainPair {
+                    fwd: raw_chain::RawChain::empty(order),
+                    rev: raw_chain::RawChain::empty(order),
+                });
+                self.cached
+                    .insert(nick.to_string(), CachedChain::new(chain));
             }
         }
+        let c = self.cached.get_mut(nick).unwrap();
+        c.touch();
+        c.dirty = true;
+        // copy-on-write if someone is still generating from the chain
+        Arc::make_mut(&mut c.chain).feed_str(msg);
     }
-    Ok(chains)
-}
 
-const PREFIX: &'static str = "!charlie";
+    /// Write back all chains modified by `learn`, returns how many were saved
+    pub fn save_dirty(&mut self) -> Fallible<usize> {
+        let mut n = 0;
+        for (nick, c) in self.cached.iter_mut().filter(|(_, c)| c.dirty) {
+            c.chain.save(&path_for_nick(&self.data_dir, nick)?)?;
+            c.dirty = false;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
 
-fn parse_irc_cmd<'a>(msg: &'a Message) -> Option<&'a str> {
+fn parse_irc_cmd<'a>(prefix: &str, msg: &'a Message) -> Option<&'a str> {
     match msg.command {
-        Command::PRIVMSG(ref _tgt, ref line) if line.starts_with(PREFIX) => {
-            let rest = &line[PREFIX.len()..].trim();
+        Command::PRIVMSG(ref _tgt, ref line) if line.starts_with(prefix) => {
+            let rest = &line[prefix.len()..].trim();
             Some(rest)
         }
         _ => None,
     }
 }
 
-fn serve(data_dir: &path::Path) -> Fallible<()> {
-    let chains = Arc::new(Mutex::new(Chains::with_path(data_dir)));
+/// Channel message to learn from, as `(nick, msg)`, with channel names
+/// compared under `cm`. Commands and messages sent by the bot itself are
+/// ignored. Of CTCP messages, only `/me` actions are kept, as what
+/// follows the nick, like in logs.
+fn parse_irc_learn<'a>(
+    irc_config: &config::IrcConfig,
+    cm: casemapping::Casemapping,
+    msg: &'a Message,
+) -> Option<(String, &'a str)> {
+    let (tgt, line) = match msg.command {
+        Command::PRIVMSG(ref tgt, ref line) => (tgt, line.as_str()),
+        _ => return None,
+    };
+    let tgt = cm.fold(tgt);
+    if !irc_config.channels.iter().any(|c| cm.fold(c) == tgt)
+        || line.starts_with(&irc_config.prefix)
+    {
+        return None;
+    }
+    let line = if line.starts_with('\x01') {
+        line.trim_matches('\x01').strip_prefix("ACTION ")?.trim()
+    } else {
+        line.trim()
+    };
+    let nick = log_parse::normalize_nick(msg.source_nickname()?);
+    let own = nick == log_parse::normalize_nick(&irc_config.nickname);
+    if nick.is_empty() || own || line.is_empty() {
+        None
+    } else {
+        Some((nick, line))
+    }
+}
+
+fn save_learnt(chains: &Mutex<Chains>) {
+    match chains.lock().unwrap().save_dirty() {
+        Ok(0) => (),
+        Ok(n) => println!("saved {} learnt chains", n),
+        Err(e) => println!("error while saving learnt chains: {}", e),
+    }
+}
+
+fn serve(config: &config::Config) -> Fallible<()> {
+    let irc_config = config.irc()?;
+    let aliases = aliases::Aliases::from_config(config)?;
+    // chains may have been named with another mapping or aliases
+    aliases.rename_chains(&config.data_dir)?;
+    let chains = {
+        let mut c = Chains::with_path(&config.data_dir);
+        c.order = config.chain_order();
+        c.backoff = config.backoff.as_ref().map(|b| b.weights.clone());
+        c.aliases = aliases;
+        c.set_orders(config.orders.clone());
+        Arc::new(Mutex::new(c))
+    };
     println!(
         "known nicks: {:?}",
         chains.lock().unwrap().nicks().iter().collect::<Vec<_>>()
     );
 
-    let config = Config {
-        nickname: Some("charliebot".to_string()),
-        server: Some("chat.freenode.org".to_string()),
-        port: Some(7000),
-        channels: Some(vec!["#arch-fr-free".to_string()]),
-        use_ssl: Some(true),
-        ..Config::default()
-    };
-
-    let client = IrcClient::from_config(config).map_err(|e| e.to_string())?;
+    let client =
+        IrcClient::from_config(irc_config.to_client_config()).map_err(|e| e.to_string())?;
     client.identify().map_err(|e| e.to_string())?;
 
     // thread to cleanup chains regularly
@@ -200,13 +428,59 @@ fn serve(data_dir: &path::Path) -> Fallible<()> {
         })
     };
 
+    if irc_config.learn {
+        fs::create_dir_all(&config.data_dir)?;
+        // thread to save learnt chains regularly
+        let c = chains.clone();
+        let interval = time::Duration::from_secs(irc_config.save_interval);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            save_learnt(&c);
+        });
+
+        // save learnt chains before exiting on ^C or `kill`
+        let c = chains.clone();
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        thread::spawn(move || {
+            if let Some(sig) = signals.forever().next() {
+                println!("got signal {}, exiting", sig);
+                save_learnt(&c);
+                std::process::exit(0);
+            }
+        });
+    }
+
     client
         .for_each_incoming(|message| {
             print!("{}", message);
-            if let Some(nick) = parse_irc_cmd(&message) {
-                let nick = log_parse::normalize_nick(nick);
-                println!(">>> irc command detected for {:?}", &nick);
-                if let Some(chain) = chains.lock().unwrap().find_nick(&nick) {
+            if let Command::Response(Response::RPL_ISUPPORT, ref args, _) = message.command {
+                if let Some(cm) = casemapping::from_isupport(args) {
+                    println!("server casemapping: {:?}", cm);
+                    if let Err(e) = chains.lock().unwrap().set_casemapping(cm) {
+                        println!("error while switching to casemapping {:?}: {}", cm, e);
+                    }
+                }
+            }
+            if irc_config.learn {
+                let mut chains = chains.lock().unwrap();
+                let cm = chains.aliases.casemapping();
+                if let Some((nick, msg)) = parse_irc_learn(irc_config, cm, &message) {
+                    chains.learn(&nick, msg);
+                }
+            }
+            if let Some(cmd) = parse_irc_cmd(&irc_config.prefix, &message) {
+                let req = match reply::Request::parse(cmd) {
+                    Some(r) => r,
+                    None => {
+                        println!(">>> cannot parse irc command {:?}", cmd);
+                        return;
+                    }
+                };
+                println!(">>> irc command detected: {:?}", &req);
+                let nick = &req.nick;
+                // not generating while holding the lock
+                let src = chains.lock().unwrap().find_source(nick);
+                if let Some(src) = src {
                     let reply_to = {
                         let r = message.response_target();
                         if r.is_none() {
@@ -215,14 +489,8 @@ fn serve(data_dir: &path::Path) -> Fallible<()> {
                             r.unwrap()
                         }
                     };
-                    // try to find a reply of adequate length
-                    let reply = chain
-                        .c
-                        .str_iter()
-                        .take(500)
-                        .skip_while(|s| s.len() < 20 || s.len() > 100)
-                        .next()
-                        .unwrap_or_else(|| "oh noes :(".to_string());
+                    let reply =
+                        reply::generate(&src, &req).unwrap_or_else(|| "oh noes :(".to_string());
                     println!(">>> reply {}", &reply);
                     client.send_privmsg(reply_to, reply).unwrap();
                 } else {
@@ -232,40 +500,217 @@ fn serve(data_dir: &path::Path) -> Fallible<()> {
         })
         .map_err(|e| e.to_string())?;
 
+    if irc_config.learn {
+        save_learnt(&chains);
+    }
     thread.join().unwrap();
     Ok(())
 }
 
-fn generate(data_dir: &path::Path, file: &str) -> Fallible<()> {
-    println!("create dir {:?}", data_dir);
-    fs::create_dir_all(data_dir)?;
-    let chains = read_file(file)?;
-    for (nick, chain) in chains.iter() {
-        if nick.trim() == "" {
-            continue;
-        }
-        let path = path_for_nick(data_dir, nick);
-        //println!("save for nick `{}` in {:?}", nick, path);
-        let mut w = std::io::BufWriter::new(File::create(path)?);
-        bincode::serialize_into(&mut w, &chain.c)?;
+fn check_config(p: &path::Path) -> Fallible<()> {
+    let config = config::Config::load(p)?;
+    println!("config {:?} is valid", p);
+    println!("data dir: {:?}", config.data_dir);
+    println!("casemapping: {:?}", config.casemapping);
+    let aliases = aliases::Aliases::from_config(&config)?;
+    println!(
+        "aliases: {} nicks, suffix stripping: {}",
+        aliases.len(),
+        config.strip_nick_suffixes
+    );
+    match config.backoff {
+        Some(ref b) => println!("order: back-off with weights {:?}", b.weights),
+        None => println!("order: {}", config.order),
+    }
+    match config.irc {
+        Some(ref irc) => println!(
+            "irc: {} on {}:{} (tls: {}), channels {:?}, prefix {:?}, learn: {}",
+            irc.nickname, irc.server, irc.port, irc.tls, irc.channels, irc.prefix, irc.learn
+        ),
+        None => println!("irc: no `[irc]` section, `serve` will not work"),
+    }
+    for name in config.formats.keys() {
+        println!("custom log format: {}", name);
     }
     Ok(())
 }
 
 fn main() -> Fallible<()> {
     let args = std::env::args();
-    let data_dir = path::Path::new(DATA_DIR);
+    let default_config = path::Path::new(config::DEFAULT_PATH);
     match args.collect::<Vec<_>>().as_slice() {
-        &[_, ref cmd] if cmd == "help" => {
-            println!("commands: help | generate $file | serve");
+        [_, cmd] if cmd == "help" => {
+            println!(
+                "commands: help | {} | serve [$config] | check-config [$config]",
+                generate::USAGE
+            );
+            println!(
+                "log formats: auto (default), {}",
+                log_parse::FORMATS.join(", ")
+            );
+            println!("default config file: {}", config::DEFAULT_PATH);
+        }
+        [_, cmd, rest @ ..] if cmd == "generate" => {
+            generate::generate(&generate::GenerateOpts::parse(rest)?)?;
+        }
+        [_, cmd] if cmd == "serve" => {
+            serve(&config::Config::load(default_config)?)?;
+        }
+        [_, cmd, conf] if cmd == "serve" => {
+            serve(&config::Config::load(path::Path::new(conf))?)?;
         }
-        &[_, ref cmd, ref file] if cmd == "generate" => {
-            generate(data_dir, file)?;
+        [_, cmd] if cmd == "check-config" => {
+            check_config(default_config)?;
         }
-        &[_, ref cmd] if cmd == "serve" => {
-            serve(data_dir)?;
+        [_, cmd, conf] if cmd == "check-config" => {
+            check_config(path::Path::new(conf))?;
         }
         _ => return Err("wrong command".into()),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn irc_config() -> config::IrcConfig {
+        let c: config::Config = "[irc]\nserver = \"irc.example.org\"\nnickname = \"Charlie\"\n\
+                                 channels = [\"#Chan[1]\"]\nprefix = \"!charlie\"\n"
+            .parse()
+            .unwrap();
+        c.irc.unwrap()
+    }
+
+    fn privmsg(prefix: &str, tgt: &str, line: &str) -> Message {
+        Message {
+            tags: None,
+            prefix: Some(prefix.to_string()),
+            command: Command::PRIVMSG(tgt.to_string(), line.to_string()),
+        }
+    }
+
+    fn learn(prefix: &str, tgt: &str, line: &str) -> Option<(String, String)> {
+        let msg = privmsg(prefix, tgt, line);
+        parse_irc_learn(&irc_config(), casemapping::Casemapping::Rfc1459, &msg)
+            .map(|(nick, line)| (nick, line.to_string()))
+    }
+
+    fn learnt(nick: &str, line: &str) -> Option<(String, String)> {
+        Some((nick.to_string(), line.to_string()))
+    }
+
+    #[test]
+    fn learn_channel_messages() {
+        assert_eq!(
+            learn("Alice!a@host", "#Chan[1]", " hello there "),
+            learnt("alice", "hello there")
+        );
+        // channels are compared under the casemapping
+        assert_eq!(
+            learn("alice!a@host", "#chan{1}", "hello"),
+            learnt("alice", "hello")
+        );
+        assert_eq!(learn("alice!a@host", "#other", "hello"), None);
+        assert_eq!(learn("alice!a@host", "Charlie", "hello"), None);
+        let notice = Message {
+            command: Command::NOTICE("#Chan[1]".into(), "hello".into()),
+            ..privmsg("alice!a@host", "#Chan[1]", "hello")
+        };
+        assert_eq!(
+            parse_irc_learn(&irc_config(), casemapping::Casemapping::Rfc1459, &notice),
+            None
+        );
+    }
+
+    #[test]
+    fn learn_ctcp() {
+        assert_eq!(
+            learn("alice!a@host", "#Chan[1]", "\x01ACTION waves at bob\x01"),
+            learnt("alice", "waves at bob")
+        );
+        assert_eq!(learn("alice!a@host", "#Chan[1]", "\x01VERSION\x01"), None);
+        assert_eq!(learn("alice!a@host", "#Chan[1]", "\x01ACTION \x01"), None);
+    }
+
+    #[test]
+    fn learn_ignores_commands_and_self() {
+        assert_eq!(learn("alice!a@host", "#Chan[1]", "!charlie bob"), None);
+        assert_eq!(learn("charlie!c@host", "#Chan[1]", "hello"), None);
+        assert_eq!(learn("alice!a@host", "#Chan[1]", "   "), None);
+        // server messages have no nick
+        assert_eq!(learn("irc.example.org", "#Chan[1]", "hello"), None);
+    }
+
+    #[test]
+    fn chain_headers() {
+        let dir = test_dir("headers");
+        let mut c = raw_chain::RawChain::empty(2);
+        c.feed(&["hello".to_string(), "there".to_string()]);
+        // chains written before headers existed
+        let old = dir.join("old.bin");
+        bincode::serialize_into(File::create(&old).unwrap(), &c).unwrap();
+        assert_eq!(
+            read_header(&mut BufReader::new(File::open(&old).unwrap())).unwrap(),
+            None
+        );
+        let (order, loaded) = load_chain::<raw_chain::RawChain>(&old).unwrap();
+        assert_eq!((order, &loaded), (None, &c));
+        assert_eq!(chain_order(&old).unwrap(), 2);
+
+        let new = dir.join("new.bin");
+        save_chain(&new, 2, &c).unwrap();
+        assert!(!new.with_extension("bin.tmp").exists());
+        let (order, loaded) = load_chain::<raw_chain::RawChain>(&new).unwrap();
+        assert_eq!((order, &loaded), (Some(2), &c));
+        assert_eq!(chain_order(&new).unwrap(), 2);
+        // a header and nothing else
+        let mut r = &fs::read(&new).unwrap()[..CHAIN_MAGIC.len() + 8];
+        assert_eq!(read_header(&mut r).unwrap(), Some(2));
+        assert!(r.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn learn_saves_dirty_chains() {
+        let dir = test_dir("learn");
+        let hello = |c: &raw_chain::RawChain| c.map[&vec![None]][&Some("hello".to_string())];
+        let mut chains = Chains::with_path(&dir);
+        chains.ttl = time::Duration::from_secs(0);
+        chains.learn("Alice", "hello there");
+        chains.learn("alice", "hello bob");
+        // unsaved chains survive cleanups
+        chains.cleanup();
+        assert!(chains.cached.contains_key("alice"));
+        assert_eq!(chains.save_dirty().unwrap(), 1);
+        assert_eq!(chains.save_dirty().unwrap(), 0);
+        chains.cleanup();
+        assert!(chains.cached.is_empty());
+        let p = path_for_nick(&dir, "alice").unwrap();
+        assert_eq!(hello(&raw_chain::RawChainPair::load(&p).unwrap().fwd), 2);
+
+        // learning again reloads the saved chain first
+        let mut chains = Chains::with_path(&dir);
+        chains.learn("alice", "hello again");
+        assert_eq!(chains.save_dirty().unwrap(), 1);
+        let saved = raw_chain::RawChainPair::load(&p).unwrap();
+        assert_eq!(hello(&saved.fwd), 3);
+        assert_eq!(saved.rev.map[&vec![None]][&Some("again".to_string())], 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn orders_follow_the_casemapping() {
+        let dir = test_dir("orders");
+        let mut chains = Chains::with_path(&dir);
+        chains.aliases = aliases::Aliases::new(casemapping::Casemapping::Ascii, false);
+        chains.set_orders(vec![("Alice[1]".to_string(), 3)].into_iter().collect());
+        assert_eq!(chains.order_for("alice[1]"), 3);
+        assert_eq!(chains.order_for("alice{1}"), 1);
+        chains
+            .set_casemapping(casemapping::Casemapping::Rfc1459)
+            .unwrap();
+        assert_eq!(chains.order_for("alice{1}"), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+/// Safe, reversible encoding of nicks into file names
+type Fallible<T> = crate::Fallible<T>;
+
+// Bytes outside of a small set of characters (which notably excludes
+// `.`, `/`, `\` and NUL) are written as `%XX`, so an encoded nick is
+// always a single path component that stays inside the data dir.
+
+/// Longest encoded nick we accept (file names are usually limited to 255 bytes,
+/// leave room for the extension)
+pub const MAX_LEN: usize = 200;
+
+fn is_safe(b: u8) -> bool {
+    matches!(
+        b,
+        b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'_'
+            | b'-'
+            | b'['
+            | b']'
+            | b'{'
+            | b'}'
+            | b'^'
+            | b'|'
+            | b'`'
+    )
+}
+
+/// Encode `nick` into a file name (without extension)
+pub fn encode(nick: &str) -> Fallible<String> {
+    if nick.is_empty() {
+        return Err("empty nick".into());
+    }
+    let mut s = String::with_capacity(nick.len());
+    for &b in nick.as_bytes() {
+        if is_safe(b) {
+            s.push(b as char)
+        } else {
+            s.push_str(&format!("%{:02X}", b))
+        }
+    }
+    if s.len() > MAX_LEN {
+        return Err(format!("nick {:?} is too long", nick).into());
+    }
+    Ok(s)
+}
+
+/// Decode a file name produced by `encode`
+pub fn decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else if is_safe(b) {
+            bytes.push(b)
+        } else {
+            return None;
+        }
+    }
+    let nick = String::from_utf8(bytes).ok()?;
+    if nick.is_empty() {
+        None
+    } else {
+        Some(nick)
+    }
+}
+
+/// Separator between a nick and a time period in chain names, as in
+/// `alice@2015`. `encode` never produces it.
+pub const PERIOD_SEP: char = '@';
+
+/// A period is a year, possibly followed by more precision (`2015-03`)
+fn is_period(s: &str) -> bool {
+    s.len() >= 4
+        && s.bytes().take(4).all(|b| b.is_ascii_digit())
+        && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Split a chain name into its nick and its period, if it has one
+pub fn split_period(name: &str) -> (&str, Option<&str>) {
+    match name.rfind(PERIOD_SEP) {
+        Some(i) if i > 0 && is_period(&name[i + 1..]) => (&name[..i], Some(&name[i + 1..])),
+        _ => (name, None),
+    }
+}
+
+/// Encode the chain name `name` (a nick, or `nick@period`) into a file name
+/// (without extension)
+pub fn encode_chain(name: &str) -> Fallible<String> {
+    match split_period(name) {
+        (nick, Some(period)) => Ok(format!("{}{}{}", encode(nick)?, PERIOD_SEP, period)),
+        (nick, None) => encode(nick),
+    }
+}
+
+/// Decode a file name produced by `encode_chain`
+pub fn decode_chain(s: &str) -> Option<String> {
+    match split_period(s) {
+        (nick, Some(period)) => Some(format!("{}{}{}", decode(nick)?, PERIOD_SEP, period)),
+        (nick, None) => decode(nick),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Component, Path};
+
+    fn check_inside(nick: &str) {
+        let data_dir = Path::new("data");
+        if let Ok(s) = encode(nick) {
+            let p = data_dir.join(&s).with_extension("bin");
+            assert_eq!(p.parent(), Some(data_dir), "nick {:?}", nick);
+            let comps: Vec<_> = p.components().collect();
+            assert_eq!(comps.len(), 2, "nick {:?} gives {:?}", nick, p);
+            assert!(comps.iter().all(|c| matches!(c, Component::Normal(_))));
+            assert!(!s.contains('\0'));
+            assert_eq!(decode(&s).as_deref(), Some(nick));
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for nick in &["alice", "foo[m]", "a|away", "élise", "100%", "a.b", "x y"] {
+            let s = encode(nick).unwrap();
+            assert_eq!(decode(&s).unwrap(), *nick);
+        }
+        assert_eq!(encode("alice").unwrap(), "alice");
+        assert_eq!(encode("foo[m]").unwrap(), "foo[m]");
+    }
+
+    #[test]
+    fn periods() {
+        assert_eq!(split_period("alice@2015"), ("alice", Some("2015")));
+        assert_eq!(split_period("alice@2015-03"), ("alice", Some("2015-03")));
+        assert_eq!(split_period("a@b"), ("a@b", None));
+        assert_eq!(split_period("@2015"), ("@2015", None));
+        assert_eq!(split_period("alice@15"), ("alice@15", None));
+        assert_eq!(encode_chain("alice@2015").unwrap(), "alice@2015");
+        assert_eq!(encode_chain("a@b").unwrap(), "a%40b");
+        assert_eq!(
+            encode_chain("a.b@2015/..").unwrap(),
+            "a%2Eb%402015%2F%2E%2E"
+        );
+        for name in &["alice@2015", "a@b@2015-03", "a@b", "élise"] {
+            let s = encode_chain(name).unwrap();
+            assert_eq!(decode_chain(&s).as_deref(), Some(*name));
+        }
+        assert_eq!(decode("alice@2015"), None);
+    }
+
+    #[test]
+    fn rejects() {
+        assert!(encode("").is_err());
+        assert!(encode(&"a".repeat(MAX_LEN + 1)).is_err());
+        assert!(encode(&"a".repeat(MAX_LEN)).is_ok());
+        assert_eq!(decode("../x"), None);
+        assert_eq!(decode("%2"), None);
+        assert_eq!(decode(""), None);
+    }
+
+    #[test]
+    fn stays_inside_data_dir() {
+        for nick in &[
+            "..",
+            ".",
+            "../../etc/passwd",
+            "/etc/passwd",
+            "a/../../b",
+            "..\\..\\x",
+            "C:\\x",
+            "a\0b",
+            "%2e%2e",
+            "~root",
+        ] {
+            check_inside(nick)
+        }
+        // all short strings over an alphabet of troublesome characters
+        let alphabet = ['.', '/', '\\', '\0', '%', 'a', ':'];
+        let mut cur = vec![String::new()];
+        for _ in 0..4 {
+            let mut next = vec![];
+            for s in cur.iter() {
+                for c in alphabet.iter() {
+                    let mut s = s.clone();
+                    s.push(*c);
+                    check_inside(&s);
+                    next.push(s);
+                }
+            }
+            cur = next;
+        }
+    }
+}
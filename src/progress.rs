@@ -0,0 +1,137 @@
+/// Progress of a long `generate` run, printed on stderr
+use std::{
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time,
+};
+
+/// Time between two progress lines, on a terminal or in a log file
+const INTERVAL_TTY: time::Duration = time::Duration::from_secs(1);
+const INTERVAL_LOG: time::Duration = time::Duration::from_secs(10);
+
+pub struct Progress {
+    start: time::Instant,
+    total: Option<u64>, // bytes to read, unknown when reading stdin
+    bytes: AtomicU64,
+    lines: AtomicU64,
+    tty: bool, // rewrite the same line instead of printing new ones
+    last: Mutex<time::Instant>,
+}
+
+/// `n` bytes, in a readable unit
+fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut x = n as f64;
+    let mut unit = 0;
+    while x >= 1024. && unit + 1 < UNITS.len() {
+        x /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", x, UNITS[unit])
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m{:02}s", s / 3600, s / 60 % 60, s % 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+impl Progress {
+    pub fn new(total: Option<u64>) -> Self {
+        let now = time::Instant::now();
+        Progress {
+            start: now,
+            total,
+            bytes: AtomicU64::new(0),
+            lines: AtomicU64::new(0),
+            tty: std::io::stderr().is_terminal(),
+            last: Mutex::new(now),
+        }
+    }
+
+    /// Account for `bytes` and `lines` more being read,
+    /// and print the progress if it was not printed recently
+    pub fn add(&self, bytes: u64, lines: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.lines.fetch_add(lines, Ordering::Relaxed);
+        // another thread is already printing
+        let mut last = match self.last.try_lock() {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        let interval = if self.tty { INTERVAL_TTY } else { INTERVAL_LOG };
+        if last.elapsed() >= interval {
+            *last = time::Instant::now();
+            self.print();
+        }
+    }
+
+    fn print(&self) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let lines = self.lines.load(Ordering::Relaxed);
+        let secs = self.elapsed().as_secs_f64();
+        let rate = bytes as f64 / secs.max(0.001);
+        let mut s = human_bytes(bytes);
+        if let Some(total) = self.total {
+            let pct = 100 * bytes / total.max(1);
+            s.push_str(&format!(" / {} ({}%)", human_bytes(total), pct.min(100)));
+        }
+        s.push_str(&format!(
+            ", {} lines, {}/s",
+            lines,
+            human_bytes(rate as u64)
+        ));
+        if let Some(total) = self.total {
+            if rate > 0. {
+                let eta = total.saturating_sub(bytes) as f64 / rate;
+                s.push_str(&format!(", ETA {}", human_duration(eta as u64)));
+            }
+        }
+        if self.tty {
+            eprint!("\r\x1b[K{}", s);
+        } else {
+            eprintln!("progress: {}", s);
+        }
+    }
+
+    /// Clear the progress line
+    pub fn finish(&self) {
+        if self.tty {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    pub fn elapsed(&self) -> time::Duration {
+        self.start.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_units() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1024), "1.0 KiB");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 << 20), "5.0 MiB");
+        assert_eq!(human_bytes(3 << 40), "3072.0 GiB");
+        assert_eq!(human_duration(0), "0s");
+        assert_eq!(human_duration(59), "59s");
+        assert_eq!(human_duration(60), "1m00s");
+        assert_eq!(human_duration(3599), "59m59s");
+        assert_eq!(human_duration(3600), "1h00m00s");
+        assert_eq!(human_duration(90061), "25h01m01s");
+    }
+}
@@ -0,0 +1,274 @@
+/// Direct access to the transitions of a `markov::Chain`
+use {
+    crate::{load_chain, reverse_path, save_chain},
+    std::{
+        collections::{HashMap, HashSet},
+        mem, path,
+    },
+};
+
+type Fallible<T> = crate::Fallible<T>;
+
+type Token = Option<String>;
+
+/// Bytes taken by an allocation of `n` bytes, with the allocator's header
+/// and rounding
+fn alloc_size(n: usize) -> usize {
+    if n == 0 {
+        0
+    } else {
+        ((n + 8 + 15) & !15).max(32)
+    }
+}
+
+/// Bytes taken in a hash table by an entry of `n` bytes: tables have one
+/// control byte per bucket, are at most 7/8 full, and double when they grow
+fn table_entry_size(n: usize) -> usize {
+    (n + 1) * 16 / 7
+}
+
+/// Heap bytes of the text of `t`
+fn token_size(t: &Token) -> usize {
+    t.as_ref().map_or(0, |s| alloc_size(s.len()))
+}
+
+/// Bytes taken by a new state and its empty table of next tokens, which
+/// starts with room for 3 tokens
+fn state_size(state: &[Token]) -> usize {
+    let entry = mem::size_of::<(Token, usize)>();
+    table_entry_size(mem::size_of::<(Vec<Token>, HashMap<Token, usize>)>())
+        + alloc_size(mem::size_of_val(state))
+        + state.iter().map(token_size).sum::<usize>()
+        + alloc_size(4 * (entry + 1) + 16)
+}
+
+/// Same layout as `markov::Chain<String>`, which is also the layout of
+/// `data/*.bin` files. `markov` does not expose its transitions, so we go
+/// through the serialized form to read or combine them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RawChain {
+    /// `order` tokens (`None` for the start or end of a sentence) -> next token -> count
+    pub map: HashMap<Vec<Option<String>>, HashMap<Option<String>, usize>>,
+    pub order: usize,
+}
+
+impl RawChain {
+    /// Chain of order `order` with no transitions
+    pub fn empty(order: usize) -> Self {
+        let mut map = HashMap::new();
+        // the start state is always there, as in the chain files written by
+        // `markov::Chain` before this module replaced it
+        map.insert(vec![None; order], HashMap::new());
+        RawChain { map, order }
+    }
+
+    /// Read a chain file directly
+    pub fn load(p: &path::Path) -> Fallible<Self> {
+        Ok(load_chain(p)?.1)
+    }
+
+    /// The chain of the lower order `order` with the same transitions: counts
+    /// of the states that differ only by their oldest tokens are summed
+    pub fn reduce(self, order: usize) -> RawChain {
+        if order >= self.order {
+            return self;
+        }
+        let mut r = RawChain::empty(order);
+        for (prefix, nexts) in self.map {
+            let ours = r
+                .map
+                .entry(prefix[self.order - order..].to_vec())
+                .or_default();
+            for (next, n) in nexts {
+                *ours.entry(next).or_insert(0) += n;
+            }
+        }
+        r
+    }
+
+    /// Add the transitions of a sentence, like `markov::Chain::feed`.
+    /// Returns an estimate of the memory this took, in bytes: repeated
+    /// transitions take none.
+    pub fn feed(&mut self, tokens: &[String]) -> usize {
+        if tokens.is_empty() {
+            return 0;
+        }
+        let mut toks = vec![None; self.order];
+        toks.extend(tokens.iter().cloned().map(Some));
+        toks.push(None);
+        let mut added = 0;
+        for w in toks.windows(self.order + 1) {
+            let (state, next) = (&w[..self.order], &w[self.order]);
+            if !self.map.contains_key(state) {
+                added += state_size(state);
+                self.map.insert(state.to_vec(), HashMap::new());
+            }
+            let nexts = self.map.get_mut(state).unwrap();
+            match nexts.get_mut(next) {
+                Some(n) => *n += 1,
+                None => {
+                    // the first 3 fit in the table counted with the state
+                    if nexts.len() >= 3 {
+                        added += table_entry_size(mem::size_of::<(Token, usize)>());
+                    }
+                    added += token_size(next);
+                    nexts.insert(next.clone(), 1);
+                }
+            }
+        }
+        added
+    }
+
+    /// Number of distinct tokens
+    pub fn vocabulary(&self) -> usize {
+        let mut words = HashSet::new();
+        for nexts in self.map.values() {
+            words.extend(nexts.keys().filter_map(|w| w.as_ref()));
+        }
+        words.len()
+    }
+
+    /// Add the transition counts of `other` to ours
+    pub fn merge(&mut self, other: &RawChain) -> Fallible<()> {
+        if self.order != other.order {
+            return Err(format!(
+                "cannot merge chains of order {} and {}",
+                self.order, other.order
+            )
+            .into());
+        }
+        for (prefix, nexts) in other.map.iter() {
+            let ours = self.map.entry(prefix.clone()).or_default();
+            for (next, n) in nexts.iter() {
+                *ours.entry(next.clone()).or_insert(0) += n;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A chain and its reverse chain, trained on reversed sentences to grow
+/// sentences leftwards from a word
+#[derive(Clone)]
+pub struct RawChainPair {
+    pub fwd: RawChain,
+    pub rev: RawChain,
+}
+
+impl RawChainPair {
+    /// Chains of order `order` with no transitions
+    pub fn empty(order: usize) -> Self {
+        RawChainPair {
+            fwd: RawChain::empty(order),
+            rev: RawChain::empty(order),
+        }
+    }
+
+    /// Read the chain stored at `p` and its reverse chain, which is empty
+    /// if the chain was stored before reverse chains existed
+    pub fn load(p: &path::Path) -> Fallible<Self> {
+        let fwd = RawChain::load(p)?;
+        let rev_path = reverse_path(p);
+        let rev = if rev_path.exists() {
+            RawChain::load(&rev_path)?
+        } else {
+            RawChain::empty(fwd.order)
+        };
+        Ok(RawChainPair { fwd, rev })
+    }
+
+    pub fn reduce(self, order: usize) -> Self {
+        RawChainPair {
+            fwd: self.fwd.reduce(order),
+            rev: self.rev.reduce(order),
+        }
+    }
+
+    /// Feed the tokens of `msg` into the chain, and reversed into the
+    /// reverse chain. Returns an estimate of the
+    /// memory this took, like `RawChain::feed`.
+    pub fn feed_str(&mut self, msg: &str) -> usize {
+        let mut tokens: Vec<String> = msg.split(' ').map(|s| s.to_owned()).collect();
+        let added = self.fwd.feed(&tokens);
+        tokens.reverse();
+        added + self.rev.feed(&tokens)
+    }
+
+    pub fn merge(&mut self, other: &RawChainPair) -> Fallible<()> {
+        self.fwd.merge(&other.fwd)?;
+        self.rev.merge(&other.rev)
+    }
+
+    /// Write the chain to `p`, and its reverse chain next to it
+    pub fn save(&self, p: &path::Path) -> Fallible<()> {
+        save_chain(p, self.fwd.order, &self.fwd)?;
+        save_chain(&reverse_path(p), self.rev.order, &self.rev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, markov::Chain as MChain};
+
+    fn raw(c: &MChain<String>) -> RawChain {
+        bincode::deserialize(&bincode::serialize(c).unwrap()).unwrap()
+    }
+
+    /// Chains trained by `markov`
+    fn trained(order: usize) -> RawChainPair {
+        let (mut fwd, mut rev) = (MChain::of_order(order), MChain::of_order(order));
+        for msg in &[
+            "the cat sat on the mat",
+            "the dog sat",
+            "a cat",
+            "the cat sat",
+        ] {
+            fwd.feed_str(msg);
+            rev.feed(msg.split(' ').rev().map(|s| s.to_owned()).collect());
+        }
+        RawChainPair {
+            fwd: raw(&fwd),
+            rev: raw(&rev),
+        }
+    }
+
+    #[test]
+    fn reduce_equals_training_at_lower_order() {
+        for order in 1..=3 {
+            let reduced = trained(3).reduce(order);
+            let direct = trained(order);
+            assert_eq!(reduced.fwd, direct.fwd, "order {}", order);
+            assert_eq!(reduced.rev, direct.rev, "order {}", order);
+        }
+        // reducing to a higher order leaves the chain as is
+        assert_eq!(trained(2).reduce(3).fwd, trained(2).fwd);
+    }
+
+    #[test]
+    fn feed_str_like_chain() {
+        let mut raw = RawChainPair::empty(3);
+        for msg in &[
+            "the cat sat on the mat",
+            "the dog sat",
+            "a cat",
+            "the cat sat",
+        ] {
+            raw.feed_str(msg);
+        }
+        let expected = trained(3);
+        assert_eq!(raw.fwd, expected.fwd);
+        assert_eq!(raw.rev, expected.rev);
+    }
+
+    #[test]
+    fn feed_estimates_new_transitions() {
+        let mut c = RawChainPair::empty(2);
+        let first = c.feed_str("the cat sat");
+        // 3 new states and 4 new transitions in each direction
+        assert!(first > 2 * 3 * state_size(&[None, None]), "{}", first);
+        assert_eq!(c.feed_str("the cat sat"), 0);
+        // only "sat" -> "down" and its following states are new
+        let more = c.feed_str("the cat sat down");
+        assert!(more > 0 && more < first, "{} {}", more, first);
+    }
+}
@@ -0,0 +1,226 @@
+/// Replies to IRC commands: `!charlie nick [about word] [--short|--long]`
+use crate::{backoff::Backoff, log_parse};
+
+/// Sentences generated, each steered towards the target length, before
+/// settling for the closest one
+const TRIES: usize = 20;
+
+/// Sentences searched for a word the chain only knows with another case
+const SEARCH_TRIES: usize = 500;
+
+/// Length of the reply asked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Length {
+    Short,
+    Normal,
+    Long,
+}
+
+impl Length {
+    /// Range of lengths to aim for, in bytes
+    pub fn target(self) -> (usize, usize) {
+        match self {
+            Length::Short => (5, 40),
+            Length::Normal => (20, 100),
+            Length::Long => (100, 300),
+        }
+    }
+}
+
+/// What a command asks for
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub nick: String,          // normalized, maybe `nick@period`
+    pub about: Option<String>, // a word the reply should contain
+    pub length: Length,
+}
+
+impl Request {
+    /// Parse the arguments of a command: `nick` or `nick about word`,
+    /// followed or preceded by `--short` or `--long`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut length = Length::Normal;
+        let mut words = vec![];
+        for w in s.split_whitespace() {
+            match w {
+                "--short" => length = Length::Short,
+                "--long" => length = Length::Long,
+                _ if w.starts_with("--") => return None,
+                _ => words.push(w),
+            }
+        }
+        let (nick, about) = match words[..] {
+            [nick] => (nick, None),
+            [nick, "about", word] => (nick, Some(word.to_string())),
+            _ => return None,
+        };
+        let nick = log_parse::normalize_nick(nick);
+        if nick.is_empty() {
+            return None;
+        }
+        Some(Request {
+            nick,
+            about,
+            length,
+        })
+    }
+}
+
+/// Bytes missing from `s`, or in excess, to be in `target`
+fn distance(s: &str, (min, max): (usize, usize)) -> usize {
+    if s.len() < min {
+        min - s.len()
+    } else {
+        s.len().saturating_sub(max)
+    }
+}
+
+/// The first sentence from `gen` with a length in `target`, or the
+/// closest one. `None` if it generates only empty ones.
+fn closest<F: Fn() -> String>(gen: F, target: (usize, usize)) -> Option<String> {
+    let mut best = gen();
+    if best.is_empty() {
+        return None;
+    }
+    for _ in 1..TRIES {
+        if distance(&best, target) == 0 {
+            break;
+        }
+        let s = gen();
+        if distance(&s, target) < distance(&best, target) {
+            best = s;
+        }
+    }
+    Some(best)
+}
+
+/// Generate a reply to `req` from `src`, of the length it asks for if possible
+pub fn generate(src: &Backoff, req: &Request) -> Option<String> {
+    let target = req.length.target();
+    let word = match req.about {
+        Some(ref w) => w,
+        None => return closest(|| src.sentence(target), target),
+    };
+    // `word` anywhere in the sentence; only what follows it is generated
+    // if there is no reverse chain (chains generated before it existed)
+    let seeds = src.seeds(word);
+    if let Some(s) = closest(|| src.around(&seeds, target), target) {
+        return Some(s);
+    }
+    // otherwise, a sentence that contains it with another case
+    let word = word.to_lowercase();
+    let mut found: Option<String> = None;
+    for s in (0..SEARCH_TRIES).map(|_| src.sentence(target)) {
+        if s.split(' ').any(|w| w.to_lowercase() == word) {
+            if distance(&s, target) == 0 {
+                return Some(s);
+            }
+            match found {
+                Some(ref f) if distance(&s, target) >= distance(f, target) => (),
+                _ => found = Some(s),
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::raw_chain::RawChainPair};
+
+    /// Replies from a chain fed with `msgs`
+    fn reply(msgs: &[&str], about: Option<&str>, length: Length) -> Option<String> {
+        let mut c = RawChainPair::empty(1);
+        for msg in msgs {
+            c.feed_str(msg);
+        }
+        generate(&Backoff::single(c), &req("alice", about, length)?)
+    }
+
+    fn req(nick: &str, about: Option<&str>, length: Length) -> Option<Request> {
+        Some(Request {
+            nick: nick.to_string(),
+            about: about.map(|w| w.to_string()),
+            length,
+        })
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Request::parse("Alice"), req("alice", None, Length::Normal));
+        assert_eq!(
+            Request::parse(" @alice  "),
+            req("alice", None, Length::Normal)
+        );
+        assert_eq!(
+            Request::parse("alice about Cats"),
+            req("alice", Some("Cats"), Length::Normal)
+        );
+        assert_eq!(
+            Request::parse("alice@2015"),
+            req("alice@2015", None, Length::Normal)
+        );
+        assert_eq!(
+            Request::parse("alice@2015-06 about cats"),
+            req("alice@2015-06", Some("cats"), Length::Normal)
+        );
+    }
+
+    #[test]
+    fn parse_length() {
+        let short = req("alice", Some("cats"), Length::Short);
+        assert_eq!(Request::parse("--short alice about cats"), short);
+        assert_eq!(Request::parse("alice --short about cats"), short);
+        assert_eq!(Request::parse("alice about --short cats"), short);
+        assert_eq!(Request::parse("alice about cats --short"), short);
+        assert_eq!(
+            Request::parse("alice --long"),
+            req("alice", None, Length::Long)
+        );
+        // the last one wins
+        assert_eq!(
+            Request::parse("--long alice --short"),
+            req("alice", None, Length::Short)
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in &[
+            "",
+            "--short",
+            "alice --loud",
+            "alice about",
+            "about cats",
+            "alice about cats dogs",
+            "alice bob",
+            "<>",
+        ] {
+            assert_eq!(Request::parse(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn replies_of_the_length_asked() {
+        // sentences of any number of `blah`s, mostly short ones
+        let msgs = ["blah", "blah blah"];
+        for &length in &[Length::Short, Length::Normal, Length::Long] {
+            for about in &[None, Some("blah")] {
+                for _ in 0..20 {
+                    let s = reply(&msgs, *about, length).unwrap();
+                    assert_eq!(distance(&s, length.target()), 0, "{:?}: {:?}", length, s);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn about_word_in_another_case() {
+        let msgs = ["i like cats", "i like dogs"];
+        for _ in 0..20 {
+            let s = reply(&msgs, Some("Cats"), Length::Normal).unwrap();
+            assert_eq!(s, "i like cats");
+        }
+        assert_eq!(reply(&msgs, Some("cows"), Length::Normal), None);
+    }
+}
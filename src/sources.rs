@@ -0,0 +1,137 @@
+/// Record of the log files already fed into the chains of a data dir
+use std::{collections::HashMap, fs, path};
+
+type Fallible<T> = crate::Fallible<T>;
+
+const FILE_NAME: &str = "sources.txt";
+
+pub struct Sources {
+    path: path::PathBuf,
+    files: HashMap<path::PathBuf, Source>, // by canonical path
+}
+
+/// What was ingested of a log file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Source {
+    pub size: u64,
+    pub records: usize, // read from the file
+    pub filter: String, // options that left entries out, see `GenerateOpts`
+}
+
+/// Canonical path and size of the log file `f`
+pub fn key(f: &path::Path) -> Fallible<(path::PathBuf, u64)> {
+    let p = fs::canonicalize(f)?;
+    let size = fs::metadata(&p)?.len();
+    Ok((p, size))
+}
+
+impl Sources {
+    /// No source ingested yet in `data_dir`
+    pub fn new(data_dir: &path::Path) -> Self {
+        Sources {
+            path: data_dir.join(FILE_NAME),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Read the sources recorded in `data_dir`, if any.
+    /// The file contains one `<size>\t<records>\t<filter>\t<path>` line per source.
+    pub fn load(data_dir: &path::Path) -> Fallible<Self> {
+        let mut s = Sources::new(data_dir);
+        if !s.path.exists() {
+            return Ok(s);
+        }
+        for (i, line) in fs::read_to_string(&s.path)?.lines().enumerate() {
+            let mut splitter = line.splitn(4, '\t');
+            match (
+                splitter.next().and_then(|n| n.parse().ok()),
+                splitter.next().and_then(|n| n.parse().ok()),
+                splitter.next(),
+                splitter.next(),
+            ) {
+                (Some(size), Some(records), Some(filter), Some(p)) => {
+                    let filter = filter.to_string();
+                    s.files.insert(
+                        p.into(),
+                        Source {
+                            size,
+                            records,
+                            filter,
+                        },
+                    );
+                }
+                _ => return Err(format!("{:?}:{}: invalid line", s.path, i + 1).into()),
+            }
+        }
+        Ok(s)
+    }
+
+    /// What was ingested of `p`, if it was
+    pub fn get(&self, p: &path::Path) -> Option<&Source> {
+        self.files.get(p)
+    }
+
+    /// Record that `records` records of `p` were ingested under `filter`,
+    /// when it had `size` bytes
+    pub fn add(&mut self, p: path::PathBuf, size: u64, records: usize, filter: &str) {
+        let filter = filter.to_string();
+        self.files.insert(
+            p,
+            Source {
+                size,
+                records,
+                filter,
+            },
+        );
+    }
+
+    pub fn save(&self) -> Fallible<()> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by_key(|&(p, _)| p);
+        let mut out = String::new();
+        for (p, source) in files {
+            out.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                source.size,
+                source.records,
+                source.filter,
+                p.display()
+            ));
+        }
+        fs::write(&self.path, out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::test_dir};
+
+    #[test]
+    fn round_trip() {
+        let dir = test_dir("sources");
+        let mut s = Sources::new(&dir);
+        s.add("/logs/b.log".into(), 100, 7, "--since 2020 --no-actions");
+        s.add("/logs/with\ttab.log".into(), 20, 1, "");
+        s.add("/logs/a.log".into(), 5, 0, "");
+        s.save().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join(FILE_NAME)).unwrap(),
+            "5\t0\t\t/logs/a.log\n\
+             100\t7\t--since 2020 --no-actions\t/logs/b.log\n\
+             20\t1\t\t/logs/with\ttab.log\n"
+        );
+        let loaded = Sources::load(&dir).unwrap();
+        assert_eq!(loaded.files, s.files);
+        for invalid in &[
+            "100\n",
+            "100\t/logs/a.log\n",
+            "100\t7\t/logs/a.log\n",
+            "big\t1\t\t/logs/a.log\n",
+        ] {
+            fs::write(dir.join(FILE_NAME), invalid).unwrap();
+            assert!(Sources::load(&dir).is_err(), "{:?}", invalid);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
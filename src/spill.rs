@@ -0,0 +1,139 @@
+/// Partial chains written to disk when `generate` exceeds its memory budget
+use {
+    crate::{nick_file, raw_chain::RawChainPair},
+    std::{
+        collections::HashMap,
+        fs, path,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+};
+
+type Fallible<T> = crate::Fallible<T>;
+
+/// Directory of spilled chains: one subdirectory per spill,
+/// containing `<nick>.bin` and `<nick>.rev.bin` files per nick
+pub struct Spill {
+    dir: path::PathBuf,
+    next: AtomicUsize, // number of the next spill
+}
+
+impl Spill {
+    /// Create a fresh spill directory in `data_dir`
+    pub fn new(data_dir: &path::Path) -> Fallible<Self> {
+        let dir = data_dir.join(format!(".spill-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(Spill {
+            dir,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Write `chains` to disk, leaving it empty
+    pub fn spill(&self, chains: &mut HashMap<String, RawChainPair>) -> Fallible<()> {
+        let n = self.next.fetch_add(1, Ordering::SeqCst);
+        let dir = self.dir.join(n.to_string());
+        fs::create_dir_all(&dir)?;
+        info!("spill {} chains to {:?}", chains.len(), dir);
+        for (nick, c) in chains.drain() {
+            match nick_file::encode(&nick) {
+                Ok(name) => c.save(&dir.join(name).with_extension("bin"))?,
+                Err(e) => info!("skip nick {:?}: {}", nick, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Spilled files, by nick (reverse chains not included)
+    pub fn files(&self) -> Fallible<HashMap<String, Vec<path::PathBuf>>> {
+        let mut files: HashMap<String, Vec<path::PathBuf>> = HashMap::new();
+        for d in fs::read_dir(&self.dir)? {
+            for f in fs::read_dir(d?.path())? {
+                let f = f?.path();
+                let nick = f
+                    .file_stem()
+                    .and_then(|s| nick_file::decode(&s.to_string_lossy()));
+                if let Some(nick) = nick {
+                    files.entry(nick).or_default().push(f)
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Remove the spill directory, reporting errors that dropping it
+    /// would ignore
+    pub fn remove(self) -> Fallible<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::test_dir};
+
+    fn feed(chains: &mut HashMap<String, RawChainPair>, nick: &str, msg: &str) {
+        chains
+            .entry(nick.to_string())
+            .or_insert_with(|| RawChainPair::empty(2))
+            .feed_str(msg);
+    }
+
+    #[test]
+    fn spilled_chains_reload_whole() {
+        let data_dir = test_dir("spill");
+        let spill = Spill::new(&data_dir).unwrap();
+        let lines = [
+            ("alice", "hello there"),
+            ("bob", "hi alice"),
+            ("alice", "how are you"),
+            ("alice", "hello bob how are you"),
+        ];
+        let mut whole = HashMap::new();
+        for part in lines.chunks(2) {
+            let mut chains = HashMap::new();
+            for &(nick, msg) in part {
+                feed(&mut whole, nick, msg);
+                feed(&mut chains, nick, msg);
+            }
+            spill.spill(&mut chains).unwrap();
+            assert!(chains.is_empty());
+        }
+        let files = spill.files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["alice"].len(), 2);
+        for (nick, files) in files {
+            let mut raw = RawChainPair::load(&files[0]).unwrap();
+            for f in files[1..].iter() {
+                raw.merge(&RawChainPair::load(f).unwrap()).unwrap();
+            }
+            assert_eq!(raw.fwd, whole[&nick].fwd, "{}", nick);
+            assert_eq!(raw.rev, whole[&nick].rev, "{}", nick);
+        }
+        spill.remove().unwrap();
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn dropped_spill_is_removed() {
+        let data_dir = test_dir("spill-drop");
+        let spill = Spill::new(&data_dir).unwrap();
+        let mut chains = HashMap::new();
+        feed(&mut chains, "alice", "hello there");
+        spill.spill(&mut chains).unwrap();
+        let dir = spill.dir.clone();
+        assert!(dir.exists());
+        drop(spill);
+        assert!(!dir.exists());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}